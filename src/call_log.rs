@@ -0,0 +1,156 @@
+use crate::phone::PhoneLine;
+use chrono::{DateTime, Local, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// One attempt at dialling a customer.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CallRecord {
+    pub timestamp: DateTime<Utc>,
+    pub customer_id: Uuid,
+    pub number: String,
+    pub line: PhoneLine,
+    /// Why the phone did not take the call, `None` when it did
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl CallRecord {
+    pub fn new(customer_id: Uuid, number: String, line: PhoneLine, result: Result<(), String>) -> CallRecord {
+        CallRecord {
+            timestamp: Utc::now(),
+            customer_id,
+            number,
+            line,
+            error: result.err(),
+        }
+    }
+}
+
+impl Display for CallRecord {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} [dialled] {} on {:?}, {}",
+               self.timestamp.with_timezone(&Local).format("%Y-%m-%d %H:%M"),
+               self.number,
+               self.line,
+               self.error.as_deref().map(|e| format!("failed: {}", e)).unwrap_or_else(|| "ok".to_string()))
+    }
+}
+
+/// Every dial attempt, kept as JSON lines next to the contacts so a record
+/// is only ever appended. A log made with `new` is kept in memory only.
+pub struct CallLog {
+    path: Option<PathBuf>,
+    records: Vec<CallRecord>,
+    last_called: HashMap<Uuid, DateTime<Utc>>,
+}
+
+impl CallLog {
+    /// A log kept in memory only, for the sample data.
+    pub fn new() -> CallLog {
+        CallLog { path: None, records: Vec::new(), last_called: HashMap::new() }
+    }
+
+    /// `contacts.json` and `contacts.db` both log to `contacts.calls.jsonl`.
+    pub fn path_for(contacts_path: &Path) -> PathBuf {
+        contacts_path.with_extension("calls.jsonl")
+    }
+
+    /// Reads the log, there is none yet before the first call. Lines that
+    /// can not be read are skipped and a log that can not be read at all is
+    /// started afresh, the calls are never worth losing the contacts over.
+    pub fn open(path: PathBuf) -> CallLog {
+        let mut log = CallLog::new();
+        match fs::read(&path) {
+            Ok(bytes) => {
+                for line in String::from_utf8_lossy(&bytes).lines().filter(|l| !l.trim().is_empty()) {
+                    match serde_json::from_str::<CallRecord>(line) {
+                        Ok(record) => log.remember(record),
+                        // A line cut short by a crash should not lose the rest
+                        Err(e) => log::warn!("Skipping call log entry in {}: {}", path.display(), e),
+                    }
+                }
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => {},
+            Err(e) => log::warn!("Could not read the call log {}, starting an empty one: {}", path.display(), e),
+        }
+        log.path = Some(path);
+
+        log
+    }
+
+    /// Appends the call to the log file. The call is kept for the session
+    /// even when writing it fails.
+    pub fn record(&mut self, record: CallRecord) -> io::Result<()> {
+        let result = match self.path.as_ref() {
+            Some(path) => OpenOptions::new().create(true).append(true).open(path)
+                .and_then(|mut file| writeln!(file, "{}", serde_json::to_string(&record)?)),
+            None => Ok(()),
+        };
+        self.remember(record);
+
+        result
+    }
+
+    fn remember(&mut self, record: CallRecord) {
+        let last = self.last_called.entry(record.customer_id).or_insert(record.timestamp);
+        *last = (*last).max(record.timestamp);
+        self.records.push(record);
+    }
+
+    /// The calls to one customer, newest first.
+    pub fn for_customer(&self, id: Uuid) -> Vec<&CallRecord> {
+        self.records.iter().rev().filter(|r| r.customer_id == id).collect()
+    }
+
+    /// The last `count` calls to anyone, newest first.
+    pub fn recent(&self, count: usize) -> Vec<&CallRecord> {
+        self.records.iter().rev().take(count).collect()
+    }
+
+    pub fn last_called(&self, id: Uuid) -> Option<DateTime<Utc>> {
+        self.last_called.get(&id).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn bad_lines_are_skipped() {
+        let dir = TestDir::new("calls_bad_lines");
+        let path = CallLog::path_for(&dir.join("contacts.json"));
+        let id = Uuid::new_v4();
+        let good = serde_json::to_string(&CallRecord::new(id, "07 3333 4444".to_string(), PhoneLine::Line1, Ok(()))).unwrap();
+        let mut contents = format!("{}\n{{\"timestamp\": \"cut sh\n", good).into_bytes();
+        contents.extend(b"\xff\xfe not text\n");
+        fs::write(&path, contents).unwrap();
+
+        let mut log = CallLog::open(path.clone());
+        assert_eq!(log.for_customer(id).len(), 1);
+
+        // New calls are still appended after the damage
+        log.record(CallRecord::new(id, "07 3333 4444".to_string(), PhoneLine::Line1, Err("busy".to_string()))).unwrap();
+        let log = CallLog::open(path);
+        assert_eq!(log.for_customer(id).len(), 2);
+        assert_eq!(log.for_customer(id)[0].error.as_deref(), Some("busy"));
+    }
+
+    #[test]
+    fn unreadable_log_starts_empty() {
+        let dir = TestDir::new("calls_unreadable");
+        // A directory where the log should be can not be read as one
+        let path = dir.join("contacts.calls.jsonl");
+        fs::create_dir(&path).unwrap();
+
+        let log = CallLog::open(path);
+        assert!(log.recent(10).is_empty());
+    }
+}
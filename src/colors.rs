@@ -6,10 +6,15 @@ pub struct ColorScheme {
     pub black: Color,
     pub grey: Color,
     pub white: Color,
+    #[allow(dead_code)]
     pub light_grey: Color,
+    #[allow(dead_code)]
     pub red: Color,
+    #[allow(dead_code)]
     pub green: Color,
+    #[allow(dead_code)]
     pub yellow: Color,
+    #[allow(dead_code)]
     pub blue: Color,
     pub magenta: Color,
     pub cyan: Color,
@@ -0,0 +1,384 @@
+use crate::call_log::CallLog;
+use crate::contacts_file::ContactsFile;
+use crate::crypto::FileKey;
+use crate::csv_export;
+use crate::csv_import::{self, CsvImport};
+use crate::customer::{Customer, CustomerField};
+use crate::grandstream::{self, AccountMap};
+use crate::json_storage::JsonStorage;
+use crate::scroll_buffer::Config;
+use crate::storage::{self, Storage, StorageKind};
+use crate::vcard;
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+/// Loads the config for a command line subcommand, the defaults are used
+/// when there is no config file yet.
+fn load_config(config_path: &Path) -> Config {
+    match Config::load(config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            log::info!("Using the default config, could not load {}: {}", config_path.display(), e);
+            Config::default()
+        }
+    }
+}
+
+pub fn convert(source: PathBuf, destination: PathBuf, from: Option<StorageKind>, to: Option<StorageKind>,
+               config_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let config = load_config(config_path);
+    let from = StorageKind::resolve(from, Some(&source), StorageKind::Json);
+    let to = StorageKind::resolve(to, Some(&destination), StorageKind::Json);
+
+    let mut source_storage = from.open(source.clone(), config.backups)?;
+    unlock(source_storage.as_mut(), &source)?;
+    let mut destination_storage = to.open(destination.clone(), config.backups)?;
+    let count = storage::convert(source_storage.as_mut(), destination_storage.as_mut())?;
+
+    println!("Converted {} customers from {} ({:?}) to {} ({:?})",
+             count, source.display(), from, destination.display(), to);
+
+    Ok(())
+}
+
+pub fn list(query: Option<String>, file_path: Option<PathBuf>, backend: Option<StorageKind>,
+            config_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let config = load_config(config_path);
+    let kind = StorageKind::resolve(backend, file_path.as_deref(), config.backend);
+    let file_path = file_path.unwrap_or_else(|| kind.default_path());
+
+    let mut storage = kind.open(file_path.clone(), config.backups)?;
+    unlock(storage.as_mut(), &file_path)?;
+    storage.load()?;
+    for customer in storage.query(&query.unwrap_or_default())? {
+        println!("{}", customer);
+    }
+
+    Ok(())
+}
+
+pub fn import_vcard(source: PathBuf, file_path: Option<PathBuf>, backend: Option<StorageKind>,
+                    config_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let text = fs::read_to_string(&source)?;
+    let mut storage = open_locked(file_path, backend, config_path)?;
+    let existing = load_existing(storage.as_mut())?;
+
+    let (customers, summary) = vcard::import(&text, &existing);
+    insert_linked(storage.as_mut(), existing, customers)?;
+    storage.flush()?;
+    println!("Imported {}: {}", source.display(), summary);
+
+    Ok(())
+}
+
+/// Number of customers shown before asking to import a CSV file.
+const PREVIEW_ROWS: usize = 10;
+
+#[allow(clippy::too_many_arguments)]
+pub fn import_csv(source: PathBuf, delimiter: Option<String>, encoding: Option<String>, map: Vec<String>, yes: bool,
+                  file_path: Option<PathBuf>, backend: Option<StorageKind>,
+                  config_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let custom_fields = load_config(config_path).custom_fields;
+    let delimiter = delimiter.as_deref().map(csv_import::parse_delimiter).transpose()?;
+    let mut import = CsvImport::open(source.clone(), delimiter, encoding.as_deref(), &custom_fields)?;
+    for entry in map.iter() {
+        let (column, name) = entry.rsplit_once('=')
+            .ok_or_else(|| format!("{} is not a column=field mapping", entry))?;
+        let field = match name.trim() {
+            "ignore" | "" => None,
+            name => Some(CustomerField::from_name(name, &custom_fields)
+                .ok_or_else(|| format!("there is no field {}", name))?),
+        };
+        import.set_field(column, field)?;
+    }
+
+    println!("Read {}: {}", source.display(), import.describe());
+    for row in import.mapping_rows() {
+        println!("  {}", row);
+    }
+    let (customers, errors) = import.to_customers(&custom_fields);
+    println!("Preview:");
+    for customer in customers.iter().take(PREVIEW_ROWS) {
+        println!("  {}", customer);
+    }
+    if customers.len() > PREVIEW_ROWS {
+        println!("  ... and {} more", customers.len() - PREVIEW_ROWS);
+    }
+    if !errors.is_empty() {
+        println!("{} rows failed:", errors.len());
+        for error in errors.iter() {
+            println!("  {}", error);
+        }
+    }
+    if customers.is_empty() {
+        return Err("nothing to import".into());
+    }
+    if !yes && !confirm(&format!("Import {} customers?", customers.len()))? {
+        println!("Nothing imported");
+        return Ok(());
+    }
+
+    let mut storage = open_locked(file_path, backend, config_path)?;
+    // Loaded so saving keeps the customers already there
+    let existing = load_existing(storage.as_mut())?;
+    let count = customers.len();
+    insert_linked(storage.as_mut(), existing, customers)?;
+    storage.flush()?;
+    println!("Imported {} customers, {} rows failed", count, errors.len());
+
+    Ok(())
+}
+
+/// Asks a yes or no question on the terminal, no unless answered with y.
+fn confirm(question: &str) -> io::Result<bool> {
+    print!("{} [y/N] ", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Writes the customers matching `query` as vCards to `output`, or stdout.
+pub fn export_vcard(query: Option<String>, output: Option<PathBuf>, file_path: Option<PathBuf>,
+                    backend: Option<StorageKind>, config_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let config = load_config(config_path);
+    let kind = StorageKind::resolve(backend, file_path.as_deref(), config.backend);
+    let file_path = file_path.unwrap_or_else(|| kind.default_path());
+
+    let mut storage = kind.open(file_path.clone(), config.backups)?;
+    unlock(storage.as_mut(), &file_path)?;
+    storage.load()?;
+    let customers = storage.query(&query.unwrap_or_default())?;
+    let text = vcard::export(customers.iter());
+    match output {
+        Some(output) => {
+            fs::write(&output, text)?;
+            eprintln!("Exported {} customers to {}", customers.len(), output.display());
+        },
+        None => print!("{}", text),
+    }
+
+    Ok(())
+}
+
+/// Writes the customers matching `query` as CSV to `output`, or stdout.
+pub fn export_csv(query: Option<String>, output: Option<PathBuf>, columns: Vec<String>, file_path: Option<PathBuf>,
+                  backend: Option<StorageKind>, config_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let config = load_config(config_path);
+    let columns = if columns.is_empty() { &config.csv_columns } else { &columns };
+    let columns = csv_export::columns(columns, &config.custom_fields)?;
+    let kind = StorageKind::resolve(backend, file_path.as_deref(), config.backend);
+    let file_path = file_path.unwrap_or_else(|| kind.default_path());
+
+    let mut storage = kind.open(file_path.clone(), config.backups)?;
+    unlock(storage.as_mut(), &file_path)?;
+    storage.load()?;
+    let customers = storage.query(&query.unwrap_or_default())?;
+    let text = csv_export::export(customers.iter(), &columns)?;
+    match output {
+        Some(output) => {
+            fs::write(&output, text)?;
+            eprintln!("Exported {} customers to {}", customers.len(), output.display());
+        },
+        None => print!("{}", text),
+    }
+
+    Ok(())
+}
+
+pub fn import_grandstream(source: PathBuf, file_path: Option<PathBuf>, backend: Option<StorageKind>,
+                          config_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let text = fs::read_to_string(&source)?;
+    let mut storage = open_locked(file_path, backend, config_path)?;
+    let existing = load_existing(storage.as_mut())?;
+
+    let (customers, summary) = grandstream::import(&text, &existing)?;
+    insert_linked(storage.as_mut(), existing, customers)?;
+    storage.flush()?;
+    println!("Imported {}: {}", source.display(), summary);
+
+    Ok(())
+}
+
+/// Writes the customers matching `query` as a Grandstream phonebook to
+/// `output`, or stdout.
+pub fn export_grandstream(query: Option<String>, output: Option<PathBuf>, file_path: Option<PathBuf>,
+                          backend: Option<StorageKind>, config_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let config = load_config(config_path);
+    let kind = StorageKind::resolve(backend, file_path.as_deref(), config.backend);
+    let file_path = file_path.unwrap_or_else(|| kind.default_path());
+
+    let mut storage = kind.open(file_path.clone(), config.backups)?;
+    unlock(storage.as_mut(), &file_path)?;
+    storage.load()?;
+    let customers = storage.query(&query.unwrap_or_default())?;
+    let accounts = AccountMap { accounts: &config.grandstream_accounts, default: config.line.account() };
+    let text = grandstream::export(customers.iter(), &accounts);
+    match output {
+        Some(output) => {
+            fs::write(&output, text)?;
+            eprintln!("Exported {} customers to {}", customers.len(), output.display());
+        },
+        None => print!("{}", text),
+    }
+
+    Ok(())
+}
+
+/// Opens the contacts for a subcommand that changes them, refusing while
+/// the editor has them open.
+fn open_locked(file_path: Option<PathBuf>, backend: Option<StorageKind>,
+               config_path: &Path) -> Result<Box<dyn Storage>, Box<dyn std::error::Error>> {
+    let config = load_config(config_path);
+    let kind = StorageKind::resolve(backend, file_path.as_deref(), config.backend);
+    let file_path = file_path.unwrap_or_else(|| kind.default_path());
+
+    let mut storage = kind.open(file_path.clone(), config.backups)?;
+    if !storage.lock()? {
+        return Err(format!("{} is open in another instance, close it first", file_path.display()).into());
+    }
+    unlock(storage.as_mut(), &file_path)?;
+
+    Ok(storage)
+}
+
+/// The customers not in the trash, none when there is no contacts file yet.
+fn load_existing(storage: &mut dyn Storage) -> Result<Vec<Customer>, Box<dyn std::error::Error>> {
+    match storage.load() {
+        Ok(customers) => Ok(customers.into_iter().filter(|c| c.deleted_at.is_none()).collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Inserts imported customers, linking people to the company of the same
+/// name and adding the companies that are missing.
+fn insert_linked(storage: &mut dyn Storage, existing: Vec<Customer>, customers: Vec<Customer>) -> io::Result<()> {
+    let existing_ids: HashSet<Uuid> = existing.iter().map(|c| c.id).collect();
+    let new_ids: HashSet<Uuid> = customers.iter().map(|c| c.id).collect();
+    let mut all = existing;
+    all.extend(customers);
+    let changed: HashSet<Uuid> = Customer::link_companies(&mut all).into_iter().collect();
+    for customer in all.iter() {
+        if existing_ids.contains(&customer.id) {
+            if changed.contains(&customer.id) {
+                storage.update(customer)?;
+            }
+        } else if new_ids.contains(&customer.id) || changed.contains(&customer.id) {
+            storage.insert(customer)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Asks for the passphrase of an encrypted store.
+fn unlock(storage: &mut dyn Storage, file_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if storage.is_encrypted()? {
+        let passphrase = Zeroizing::new(rpassword::prompt_password(format!("Passphrase for {}: ", file_path.display()))?);
+        storage.unlock(&passphrase)?;
+    }
+
+    Ok(())
+}
+
+/// Asks for a new passphrase twice.
+fn new_passphrase() -> Result<Zeroizing<String>, Box<dyn std::error::Error>> {
+    let passphrase = Zeroizing::new(rpassword::prompt_password("New passphrase: ")?);
+    if passphrase.is_empty() {
+        return Err("the passphrase can not be empty".into());
+    }
+    let confirm = Zeroizing::new(rpassword::prompt_password("Repeat the new passphrase: ")?);
+    if passphrase != confirm {
+        return Err("the passphrases do not match".into());
+    }
+
+    Ok(passphrase)
+}
+
+/// Opens and loads a JSON contacts file for rewriting, refusing while the
+/// editor has it open.
+fn open_json(file_path: &Path, backups: usize) -> Result<JsonStorage, Box<dyn std::error::Error>> {
+    let mut storage = JsonStorage::new(file_path.to_path_buf(), backups);
+    if !storage.lock()? {
+        return Err(format!("{} is open in another instance, close it first", file_path.display()).into());
+    }
+    unlock(&mut storage, file_path)?;
+    storage.load()?;
+
+    Ok(storage)
+}
+
+pub fn encrypt(file_path: PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+    if ContactsFile::is_encrypted(&file_path)? {
+        return Err(format!("{} is already encrypted, use change-passphrase", file_path.display()).into());
+    }
+    // No backup, it would be a plain text copy of the file
+    let mut storage = open_json(&file_path, 0)?;
+    let passphrase = new_passphrase()?;
+    storage.set_key(Some(FileKey::generate(&passphrase)?));
+    storage.flush()?;
+    println!("Encrypted {}", file_path.display());
+
+    let backups = plain_backups(&file_path);
+    if backups > 0 {
+        eprintln!("Warning: {} older backups next to {} are not encrypted, delete them if they hold sensitive data",
+                  backups, file_path.display());
+    }
+    // No longer written to, calls to encrypted contacts are not logged to disk
+    let calls = CallLog::path_for(&file_path);
+    if calls.exists() {
+        eprintln!("Warning: the call log {} is not encrypted, delete it if it holds sensitive data", calls.display());
+    }
+
+    Ok(())
+}
+
+pub fn decrypt(file_path: PathBuf, config_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let config = load_config(config_path);
+    if !ContactsFile::is_encrypted(&file_path)? {
+        return Err(format!("{} is not encrypted", file_path.display()).into());
+    }
+    let mut storage = open_json(&file_path, config.backups)?;
+    storage.set_key(None);
+    storage.flush()?;
+    println!("Decrypted {}", file_path.display());
+
+    Ok(())
+}
+
+pub fn change_passphrase(file_path: PathBuf, config_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let config = load_config(config_path);
+    if !ContactsFile::is_encrypted(&file_path)? {
+        return Err(format!("{} is not encrypted, use encrypt", file_path.display()).into());
+    }
+    let mut storage = open_json(&file_path, config.backups)?;
+    let passphrase = new_passphrase()?;
+    storage.set_key(Some(FileKey::generate(&passphrase)?));
+    storage.flush()?;
+    println!("Changed the passphrase of {}, backups still use the old one", file_path.display());
+
+    Ok(())
+}
+
+/// The number of backups of `file_path` that are not encrypted.
+fn plain_backups(file_path: &Path) -> usize {
+    let file_name = file_path.file_name().and_then(|n| n.to_str()).unwrap_or("contacts.json");
+    let prefix = format!("{}.", file_name);
+    match file_path.parent().map(|p| if p.as_os_str().is_empty() { Path::new(".") } else { p }).map(fs::read_dir) {
+        Some(Ok(entries)) => entries
+            .filter_map(|e| e.ok())
+            .filter(|e| {
+                let name = e.file_name().to_string_lossy().to_string();
+                name.starts_with(&prefix) && name.ends_with(".bak")
+            })
+            .filter(|e| matches!(ContactsFile::is_encrypted(&e.path()), Ok(false)))
+            .count(),
+        _ => 0,
+    }
+}
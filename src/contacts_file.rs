@@ -0,0 +1,502 @@
+use crate::crypto::{self, FileKey};
+use crate::customer::Customer;
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+/// The newest contacts file format this build reads and writes.
+pub const CURRENT_VERSION: u32 = 3;
+
+/// Upgrades a file from version `index` to `index + 1`.
+type Migration = fn(Value) -> Result<Value, Error>;
+
+const MIGRATIONS: [Migration; CURRENT_VERSION as usize] = [
+    migrate_v0_to_v1,
+    migrate_v1_to_v2,
+    migrate_v2_to_v3,
+];
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Metadata {
+    pub saved_at: DateTime<Utc>,
+    pub app_version: String,
+}
+
+impl Metadata {
+    fn new() -> Metadata {
+        Metadata {
+            saved_at: Utc::now(),
+            app_version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ContactsFile {
+    pub version: u32,
+    pub metadata: Metadata,
+    pub customers: Vec<Customer>,
+    /// Deleted customers, kept until they are restored or purged
+    #[serde(default)]
+    pub trash: Vec<Customer>,
+}
+
+/// The borrowed form of `ContactsFile` used when saving.
+#[derive(Serialize)]
+struct ContactsFileRef<'a> {
+    version: u32,
+    metadata: Metadata,
+    customers: Vec<&'a Customer>,
+    trash: Vec<&'a Customer>,
+}
+
+impl ContactsFile {
+    /// Writes `customers` to a temporary file next to `file_path`, syncs it
+    /// and renames it over the live file, so a crash mid-save leaves either
+    /// the old or the new file but never a truncated one. The file being
+    /// replaced is kept as a timestamped backup, only the newest `backups`
+    /// of those are kept. With a `key` the file is encrypted.
+    pub fn save(customers: &[Customer], file_path: &Path, backups: usize, key: Option<&FileKey>) -> Result<(), Error> {
+        let temp_path = ContactsFile::temp_path(file_path);
+        let result = ContactsFile::write_temp(customers, &temp_path, key)
+            .and_then(|_| ContactsFile::rotate_backups(file_path, backups))
+            .and_then(|_| fs::rename(&temp_path, file_path));
+
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+            return result;
+        }
+
+        // Make the rename itself durable, not every platform can open a
+        // directory so this is best effort
+        #[cfg(unix)]
+        if let Some(parent) = file_path.parent() {
+            if let Ok(dir) = File::open(parent) {
+                let _ = dir.sync_all();
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_temp(customers: &[Customer], temp_path: &Path, key: Option<&FileKey>) -> Result<(), Error> {
+        let (trash, customers) = customers.iter().partition(|c| c.deleted_at.is_some());
+        let contents = ContactsFileRef {
+            version: CURRENT_VERSION,
+            metadata: Metadata::new(),
+            customers,
+            trash,
+        };
+        let mut contents = Zeroizing::new(serde_json::to_vec_pretty(&contents)?);
+        if let Some(key) = key {
+            contents = Zeroizing::new(key.encrypt(&contents)?);
+        }
+
+        let file = File::create(temp_path)?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(&contents)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+
+        Ok(())
+    }
+
+    fn temp_path(file_path: &Path) -> PathBuf {
+        let file_name = file_path.file_name().and_then(|n| n.to_str()).unwrap_or("contacts.json");
+        file_path.with_file_name(format!(".{}.{}.tmp", file_name, std::process::id()))
+    }
+
+    /// Copies the live file to `<name>.<timestamp>.bak` and deletes the
+    /// oldest backups beyond `backups`.
+    fn rotate_backups(file_path: &Path, backups: usize) -> Result<(), Error> {
+        if backups == 0 || !file_path.exists() {
+            return Ok(());
+        }
+        let file_name = file_path.file_name().and_then(|n| n.to_str()).unwrap_or("contacts.json");
+        let backup_path = file_path.with_file_name(format!("{}.{}.bak", file_name, Utc::now().format("%Y%m%d%H%M%S")));
+        fs::copy(file_path, backup_path)?;
+
+        let prefix = format!("{}.", file_name);
+        let mut existing: Vec<PathBuf> = match file_path.parent().map(fs::read_dir) {
+            Some(Ok(entries)) => entries
+                .filter_map(|e| e.ok())
+                .map(|e| e.path())
+                .filter(|p| {
+                    // Only `<name>.<14 digit timestamp>.bak`, migration backups are left alone
+                    p.file_name()
+                        .and_then(|n| n.to_str())
+                        .and_then(|n| n.strip_prefix(&prefix))
+                        .and_then(|n| n.strip_suffix(".bak"))
+                        .map(|stamp| stamp.len() == 14 && stamp.chars().all(|c| c.is_ascii_digit()))
+                        .unwrap_or(false)
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        existing.sort();
+        let excess = existing.len().saturating_sub(backups);
+        for old in existing.iter().take(excess) {
+            log::info!("Removing old backup {}", old.display());
+            fs::remove_file(old)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads a contacts file of any supported version. Older files are
+    /// migrated in memory after a copy of the original has been written
+    /// next to it, files from a newer version are refused. An encrypted file
+    /// needs the `key` for its passphrase.
+    pub fn load(file_path: &Path, key: Option<&FileKey>) -> Result<ContactsFile, Error> {
+        let mut contents = Zeroizing::new(fs::read(file_path)?);
+        if crypto::is_encrypted(&contents) {
+            let key = key.ok_or_else(|| Error::new(ErrorKind::PermissionDenied, format!(
+                "{} is encrypted, a passphrase is needed", file_path.display())))?;
+            contents = key.decrypt(&contents)?;
+        }
+        let mut value: Value = serde_json::from_slice(&contents)?;
+        let version = ContactsFile::version_of(&value)?;
+
+        if version > CURRENT_VERSION {
+            return Err(Error::new(ErrorKind::InvalidData, format!(
+                "{} uses file format v{} but this version of Rusty CRM only supports up to v{}, please upgrade",
+                file_path.display(), version, CURRENT_VERSION)));
+        }
+
+        if version < CURRENT_VERSION {
+            // Every load migrates again until the file is saved, one copy
+            // of the original is enough
+            match ContactsFile::migration_backup(file_path, version) {
+                Some(backup_path) => log::info!("Migrating {} from v{} to v{}, a copy is already at {}",
+                                                file_path.display(), version, CURRENT_VERSION, backup_path.display()),
+                None => {
+                    let backup_path = ContactsFile::backup_path(file_path, version);
+                    log::info!("Migrating {} from v{} to v{}, keeping a copy at {}",
+                               file_path.display(), version, CURRENT_VERSION, backup_path.display());
+                    fs::copy(file_path, &backup_path)?;
+                },
+            }
+
+            for migration in MIGRATIONS.iter().skip(version as usize) {
+                value = migration(value)?;
+            }
+        }
+
+        Ok(serde_json::from_value(value)?)
+    }
+
+    /// Whether the file at `file_path` is encrypted, false when there is no
+    /// file yet.
+    pub fn is_encrypted(file_path: &Path) -> Result<bool, Error> {
+        match fs::read(file_path) {
+            Ok(contents) => Ok(crypto::is_encrypted(&contents)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn version_of(value: &Value) -> Result<u32, Error> {
+        match value {
+            // Before the envelope the file was a bare array of customers
+            Value::Array(_) => Ok(0),
+            Value::Object(map) => map.get("version")
+                .and_then(|v| v.as_u64())
+                .map(|v| v as u32)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "contacts file has no version number")),
+            _ => Err(Error::new(ErrorKind::InvalidData, "contacts file is not a contacts list")),
+        }
+    }
+
+    /// An existing `<name>.v<version>-<timestamp>.bak` of the file.
+    fn migration_backup(file_path: &Path, version: u32) -> Option<PathBuf> {
+        let file_name = file_path.file_name().and_then(|n| n.to_str()).unwrap_or("contacts.json");
+        let prefix = format!("{}.v{}-", file_name, version);
+        let parent = file_path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        fs::read_dir(parent).ok()?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .find(|p| p.file_name()
+                .and_then(|n| n.to_str())
+                .map(|n| n.starts_with(&prefix) && n.ends_with(".bak"))
+                .unwrap_or(false))
+    }
+
+    fn backup_path(file_path: &Path, version: u32) -> PathBuf {
+        let file_name = file_path.file_name().and_then(|n| n.to_str()).unwrap_or("contacts.json");
+        file_path.with_file_name(format!("{}.v{}-{}.bak", file_name, version, Utc::now().format("%Y%m%d%H%M%S")))
+    }
+}
+
+/// Wraps the bare customer array in the versioned envelope and folds the old
+/// single `phone` string into the `phones` list.
+fn migrate_v0_to_v1(value: Value) -> Result<Value, Error> {
+    let mut customers = match value {
+        Value::Array(customers) => customers,
+        _ => return Err(Error::new(ErrorKind::InvalidData, "expected a v0 customer array")),
+    };
+
+    for customer in customers.iter_mut().filter_map(|c| c.as_object_mut()) {
+        let phone = customer.remove("phone");
+        if customer.contains_key("phones") {
+            continue;
+        }
+        let phones = match phone {
+            Some(Value::String(number)) if !number.is_empty() => {
+                vec![json!({ "label": "office", "number": number, "primary": true })]
+            },
+            _ => Vec::new(),
+        };
+        customer.insert("phones".to_string(), Value::Array(phones));
+    }
+
+    Ok(json!({
+        "version": 1,
+        "metadata": Metadata::new(),
+        "customers": customers,
+    }))
+}
+
+/// Adds the trash section.
+fn migrate_v1_to_v2(mut value: Value) -> Result<Value, Error> {
+    let contents = value.as_object_mut()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "expected a v1 contacts file"))?;
+    contents.entry("trash").or_insert_with(|| Value::Array(Vec::new()));
+    contents.insert("version".to_string(), json!(2));
+
+    Ok(value)
+}
+
+/// Splits the flat company and contact rows into companies with their
+/// people, grouping on the company name, ignoring case. Works on the raw
+/// records so it keeps reading v2 files however `Customer` changes later.
+/// People in the trash join a company in the trash before a live one.
+fn migrate_v2_to_v3(mut value: Value) -> Result<Value, Error> {
+    let contents = value.as_object_mut()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "expected a v2 contacts file"))?;
+    let mut customers = take_records(contents, "customers")?;
+    let mut trash = take_records(contents, "trash")?;
+
+    let mut live_companies = companies_by_name(&mut customers);
+    let mut trash_companies = companies_by_name(&mut trash);
+    link_people(&mut customers, &mut live_companies, &HashMap::new());
+    link_people(&mut trash, &mut trash_companies, &live_companies);
+
+    contents.insert("customers".to_string(), Value::Array(customers));
+    contents.insert("trash".to_string(), Value::Array(trash));
+    contents.insert("version".to_string(), json!(3));
+
+    Ok(value)
+}
+
+/// The id and name of each company record, by lowercased name.
+type CompanyNames = HashMap<String, (Value, Value)>;
+
+fn take_records(contents: &mut Map<String, Value>, section: &str) -> Result<Vec<Value>, Error> {
+    match contents.remove(section) {
+        Some(Value::Array(records)) => Ok(records),
+        None | Some(Value::Null) => Ok(Vec::new()),
+        Some(_) => Err(Error::new(ErrorKind::InvalidData, format!("{} is not a list of customers", section))),
+    }
+}
+
+fn trimmed<'a>(record: &'a Map<String, Value>, field: &str) -> &'a str {
+    record.get(field).and_then(|v| v.as_str()).unwrap_or("").trim()
+}
+
+fn is_linked(record: &Map<String, Value>) -> bool {
+    !record.get("company_id").unwrap_or(&Value::Null).is_null()
+}
+
+fn is_company_record(record: &Map<String, Value>) -> bool {
+    !is_linked(record) && trimmed(record, "contact_name").is_empty()
+}
+
+/// Records written before ids existed are given one here rather than on
+/// load, the people need it to point at their company.
+fn companies_by_name(records: &mut [Value]) -> CompanyNames {
+    let mut companies = CompanyNames::new();
+    for company in records.iter_mut().filter_map(|r| r.as_object_mut()).filter(|r| is_company_record(r)) {
+        let key = trimmed(company, "name").to_lowercase();
+        if key.is_empty() {
+            continue;
+        }
+        let id = company.entry("id").or_insert_with(|| json!(Uuid::new_v4())).clone();
+        let name = company.get("name").cloned().unwrap_or(Value::Null);
+        companies.entry(key).or_insert((id, name));
+    }
+    companies
+}
+
+/// Points each unlinked person at the company of their name, looking in
+/// `fallback` when `companies` has none and adding the company when
+/// neither does. An added company shares the person's dates, so one added
+/// in the trash is purged along with them.
+fn link_people(records: &mut Vec<Value>, companies: &mut CompanyNames, fallback: &CompanyNames) {
+    let mut added = Vec::new();
+    for person in records.iter_mut().filter_map(|r| r.as_object_mut()) {
+        let key = trimmed(person, "name").to_lowercase();
+        if is_company_record(person) || is_linked(person) || key.is_empty() {
+            continue;
+        }
+        let (id, name) = match companies.get(&key).or_else(|| fallback.get(&key)) {
+            Some(found) => found.clone(),
+            None => {
+                let mut company = json!({ "id": Uuid::new_v4(), "name": trimmed(person, "name"), "contact_name": null });
+                for field in ["created_at", "deleted_at"] {
+                    if let Some(date) = person.get(field) {
+                        company[field] = date.clone();
+                    }
+                }
+                let found = (company["id"].clone(), company["name"].clone());
+                companies.insert(key, found.clone());
+                added.push(company);
+                found
+            }
+        };
+        person.insert("company_id".to_string(), id);
+        person.insert("name".to_string(), name);
+    }
+    records.extend(added);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn v0_array_migrates_to_current() {
+        let dir = TestDir::new("v0");
+        let path = dir.join("contacts.json");
+        fs::write(&path, r#"[
+            {"name": "Acme", "contact_name": "Jane", "phone": "07 3333 4444"},
+            {"name": "Blank Phone", "contact_name": null, "phone": ""}
+        ]"#).unwrap();
+
+        let file = ContactsFile::load(&path, None).unwrap();
+        assert_eq!(file.version, CURRENT_VERSION);
+        assert!(file.trash.is_empty());
+        let acme = file.customers.iter().find(|c| c.get_contact_name() == "Jane").unwrap();
+        assert_eq!(acme.phones.len(), 1);
+        assert_eq!(acme.phones[0].number, "07 3333 4444");
+        assert!(acme.phones[0].primary);
+        let blank = file.customers.iter().find(|c| c.name == "Blank Phone").unwrap();
+        assert!(blank.phones.is_empty());
+
+        // The original is kept beside the migrated file
+        let backups = || -> Vec<String> {
+            let mut names: Vec<String> = fs::read_dir(dir.path()).unwrap()
+                .filter_map(|e| e.ok())
+                .map(|e| e.file_name().to_string_lossy().to_string())
+                .filter(|n| n.starts_with("contacts.json.v0-") && n.ends_with(".bak"))
+                .collect();
+            names.sort();
+            names
+        };
+        assert_eq!(backups().len(), 1);
+
+        // Only once, however often it is loaded before it is saved. Dated
+        // back so a second copy could not share its name.
+        let old = "contacts.json.v0-20200101000000.bak";
+        fs::rename(dir.join(&backups()[0]), dir.join(old)).unwrap();
+        ContactsFile::load(&path, None).unwrap();
+        assert_eq!(backups(), [old]);
+    }
+
+    #[test]
+    fn v1_gains_a_trash() {
+        let dir = TestDir::new("v1");
+        let path = dir.join("contacts.json");
+        fs::write(&path, r#"{
+            "version": 1,
+            "metadata": {"saved_at": "2024-01-01T00:00:00Z", "app_version": "0.1.0"},
+            "customers": [{"name": "Acme", "contact_name": null, "phones": []}]
+        }"#).unwrap();
+
+        let file = ContactsFile::load(&path, None).unwrap();
+        assert_eq!(file.version, CURRENT_VERSION);
+        assert_eq!(file.customers.len(), 1);
+        assert!(file.trash.is_empty());
+    }
+
+    #[test]
+    fn newer_version_is_refused() {
+        let dir = TestDir::new("newer");
+        let path = dir.join("contacts.json");
+        fs::write(&path, format!(r#"{{"version": {}, "metadata": {{"saved_at": "2024-01-01T00:00:00Z", "app_version": "9.0.0"}}, "customers": []}}"#,
+                                 CURRENT_VERSION + 1)).unwrap();
+
+        let error = ContactsFile::load(&path, None).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn save_then_load_keeps_customers_and_trash() {
+        let dir = TestDir::new("save");
+        let path = dir.join("contacts.json");
+        let mut customers = vec![Customer::new(), Customer::new()];
+        customers[0].name = "Live".to_string();
+        customers[1].name = "Deleted".to_string();
+        customers[1].deleted_at = Some(Utc::now());
+
+        ContactsFile::save(&customers, &path, 2, None).unwrap();
+        let file = ContactsFile::load(&path, None).unwrap();
+        assert_eq!(file.customers.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(), vec!["Live"]);
+        assert_eq!(file.trash.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(), vec!["Deleted"]);
+    }
+
+    #[test]
+    fn v2_people_are_grouped_under_their_company() {
+        let dir = TestDir::new("v2");
+        let path = dir.join("contacts.json");
+        fs::write(&path, r#"{
+            "version": 2,
+            "metadata": {"saved_at": "2024-01-01T00:00:00Z", "app_version": "0.1.0"},
+            "customers": [
+                {"name": "Acme", "contact_name": null},
+                {"name": "acme ", "contact_name": "Jane"},
+                {"name": "Bolt", "contact_name": "Bob", "created_at": "2020-05-01T00:00:00Z"}
+            ],
+            "trash": [
+                {"name": "Acme", "contact_name": "Tim", "deleted_at": "2024-02-01T00:00:00Z"},
+                {"name": "Old Co", "contact_name": "", "deleted_at": "2024-02-01T00:00:00Z"},
+                {"name": "old co", "contact_name": "Sam", "deleted_at": "2024-02-01T00:00:00Z"},
+                {"name": "Gone", "contact_name": "Ann", "deleted_at": "2024-03-01T00:00:00Z"}
+            ]
+        }"#).unwrap();
+
+        let file = ContactsFile::load(&path, None).unwrap();
+        let find = |customers: &[Customer], contact: &str| customers.iter()
+            .find(|c| c.get_contact_name() == contact).cloned().unwrap();
+        let company = |customers: &[Customer], name: &str| customers.iter()
+            .find(|c| c.is_company() && c.name == name).cloned().unwrap();
+
+        let acme = company(&file.customers, "Acme");
+        let jane = find(&file.customers, "Jane");
+        assert_eq!((jane.company_id, jane.name.as_str()), (Some(acme.id), "Acme"));
+        // Bolt had no company record, it gets one
+        let bolt = company(&file.customers, "Bolt");
+        let bob = find(&file.customers, "Bob");
+        assert_eq!(bob.company_id, Some(bolt.id));
+        assert_eq!(bolt.created_at, bob.created_at);
+        assert_eq!(file.customers.len(), 4);
+
+        // The trash is grouped too, a trashed company first, then a live one
+        let tim = find(&file.trash, "Tim");
+        assert_eq!(tim.company_id, Some(acme.id));
+        let old_co = company(&file.trash, "Old Co");
+        let sam = find(&file.trash, "Sam");
+        assert_eq!((sam.company_id, sam.name.as_str()), (Some(old_co.id), "Old Co"));
+        let gone = company(&file.trash, "Gone");
+        let ann = find(&file.trash, "Ann");
+        assert_eq!(ann.company_id, Some(gone.id));
+        assert_eq!(gone.deleted_at, ann.deleted_at);
+        assert_eq!(file.trash.len(), 5);
+    }
+}
@@ -0,0 +1,203 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use std::fmt::{self, Debug, Formatter};
+use std::io::{Error, ErrorKind};
+use zeroize::Zeroizing;
+
+const CIPHER: &str = "xchacha20poly1305";
+const KDF: &str = "argon2id";
+const SALT_LEN: usize = 16;
+// Binds the ciphertext to this file format
+const ASSOCIATED_DATA: &[u8] = b"rusty_crm contacts";
+
+/// Argon2id cost parameters, stored in the file so they can be raised later
+/// without breaking existing files.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct KdfParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams {
+            memory_kib: 64 * 1024,
+            iterations: 3,
+            parallelism: 1,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Header {
+    cipher: String,
+    kdf: String,
+    params: KdfParams,
+    salt: String,
+    nonce: String,
+}
+
+/// An encrypted contacts file, the plaintext is a whole versioned contacts
+/// file so migrations still run after decrypting.
+#[derive(Serialize, Deserialize, Debug)]
+struct EncryptedFile {
+    encryption: Header,
+    ciphertext: String,
+}
+
+/// The key derived from a passphrase and the salt it was derived with.
+/// Deriving is deliberately slow, so the key is kept for re-encrypting on
+/// every save, each save uses a fresh nonce.
+pub struct FileKey {
+    key: Zeroizing<[u8; 32]>,
+    salt: [u8; SALT_LEN],
+    params: KdfParams,
+}
+
+impl Debug for FileKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileKey").field("params", &self.params).finish_non_exhaustive()
+    }
+}
+
+impl FileKey {
+    /// A key for a new passphrase, with a fresh random salt.
+    pub fn generate(passphrase: &str) -> Result<FileKey, Error> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        FileKey::derive(passphrase, salt, KdfParams::default())
+    }
+
+    /// The key for an existing encrypted file, using the salt and cost
+    /// parameters stored in it. A wrong passphrase is only noticed when
+    /// decrypting.
+    pub fn for_file(contents: &[u8], passphrase: &str) -> Result<FileKey, Error> {
+        let header = parse(contents)?.encryption;
+        let salt: [u8; SALT_LEN] = decode(&header.salt)?.try_into()
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "encrypted file has a bad salt"))?;
+        FileKey::derive(passphrase, salt, header.params)
+    }
+
+    fn derive(passphrase: &str, salt: [u8; SALT_LEN], params: KdfParams) -> Result<FileKey, Error> {
+        let argon_params = Params::new(params.memory_kib, params.iterations, params.parallelism, Some(32))
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("bad key derivation parameters: {}", e)))?;
+        let mut key = Zeroizing::new([0u8; 32]);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, argon_params)
+            .hash_password_into(passphrase.as_bytes(), &salt, key.as_mut())
+            .map_err(|e| Error::other(format!("key derivation failed: {}", e)))?;
+
+        Ok(FileKey { key, salt, params })
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        let cipher = XChaCha20Poly1305::new(self.key.as_ref().into());
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher.encrypt(&nonce, Payload { msg: plaintext, aad: ASSOCIATED_DATA })
+            .map_err(|_| Error::other("encryption failed"))?;
+
+        let file = EncryptedFile {
+            encryption: Header {
+                cipher: CIPHER.to_string(),
+                kdf: KDF.to_string(),
+                params: self.params,
+                salt: BASE64.encode(self.salt),
+                nonce: BASE64.encode(nonce),
+            },
+            ciphertext: BASE64.encode(ciphertext),
+        };
+
+        Ok(serde_json::to_vec_pretty(&file)?)
+    }
+
+    /// Decrypts a file written by `encrypt`. A wrong passphrase and a
+    /// tampered file both fail with `PermissionDenied`.
+    pub fn decrypt(&self, contents: &[u8]) -> Result<Zeroizing<Vec<u8>>, Error> {
+        let file = parse(contents)?;
+        if decode(&file.encryption.salt)? != self.salt {
+            return Err(Error::new(ErrorKind::PermissionDenied, "the passphrase of the contacts file has changed"));
+        }
+        let nonce = decode(&file.encryption.nonce)?;
+        if nonce.len() != 24 {
+            return Err(Error::new(ErrorKind::InvalidData, "encrypted file has a bad nonce"));
+        }
+        let ciphertext = decode(&file.ciphertext)?;
+
+        let cipher = XChaCha20Poly1305::new(self.key.as_ref().into());
+        cipher.decrypt(XNonce::from_slice(&nonce), Payload { msg: &ciphertext, aad: ASSOCIATED_DATA })
+            .map(Zeroizing::new)
+            .map_err(|_| Error::new(ErrorKind::PermissionDenied, "wrong passphrase or the contacts file is damaged"))
+    }
+}
+
+/// Whether `contents` is an encrypted contacts file rather than a plain one.
+pub fn is_encrypted(contents: &[u8]) -> bool {
+    serde_json::from_slice::<Value>(contents)
+        .map(|value| value.get("encryption").is_some())
+        .unwrap_or(false)
+}
+
+fn parse(contents: &[u8]) -> Result<EncryptedFile, Error> {
+    let file: EncryptedFile = serde_json::from_slice(contents)?;
+    if file.encryption.cipher != CIPHER || file.encryption.kdf != KDF {
+        return Err(Error::new(ErrorKind::InvalidData, format!(
+            "unsupported encryption {} with {}", file.encryption.cipher, file.encryption.kdf)));
+    }
+
+    Ok(file)
+}
+
+fn decode(text: &str) -> Result<Vec<u8>, Error> {
+    BASE64.decode(text).map_err(|e| Error::new(ErrorKind::InvalidData, format!("encrypted file is damaged: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Cheap parameters, the defaults take seconds in a debug build
+    const TEST_PARAMS: KdfParams = KdfParams { memory_kib: 64, iterations: 1, parallelism: 1 };
+
+    fn test_key(passphrase: &str) -> FileKey {
+        FileKey::derive(passphrase, [7u8; SALT_LEN], TEST_PARAMS).unwrap()
+    }
+
+    #[test]
+    fn encrypt_then_decrypt_round_trips() {
+        let plaintext = br#"{"version": 3, "customers": []}"#;
+        let contents = test_key("correct horse").encrypt(plaintext).unwrap();
+        assert!(is_encrypted(&contents));
+        assert!(!is_encrypted(plaintext));
+
+        // As on the next start, the key comes from the passphrase and the file
+        let key = FileKey::for_file(&contents, "correct horse").unwrap();
+        assert_eq!(key.decrypt(&contents).unwrap().as_slice(), plaintext);
+    }
+
+    #[test]
+    fn wrong_passphrase_is_refused() {
+        let contents = test_key("correct horse").encrypt(b"secret").unwrap();
+
+        let key = FileKey::for_file(&contents, "battery staple").unwrap();
+        let error = key.decrypt(&contents).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn tampered_file_is_refused() {
+        let key = test_key("correct horse");
+        let mut file: EncryptedFile = serde_json::from_slice(&key.encrypt(b"secret").unwrap()).unwrap();
+        let mut ciphertext = decode(&file.ciphertext).unwrap();
+        ciphertext[0] ^= 1;
+        file.ciphertext = BASE64.encode(ciphertext);
+
+        let error = key.decrypt(&serde_json::to_vec(&file).unwrap()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+    }
+}
@@ -0,0 +1,107 @@
+use crate::custom_field::CustomFieldDef;
+use crate::customer::{Customer, CustomerField};
+use std::io::{Error, ErrorKind};
+
+/// The columns of an export, from field names like `company,phones` in the
+/// order given. Every field is written when no names are given.
+pub fn columns(names: &[String], custom_fields: &[CustomFieldDef]) -> Result<Vec<CustomerField>, Error> {
+    let names: Vec<&str> = names.iter()
+        .flat_map(|name| name.split(','))
+        .map(|name| name.trim())
+        .filter(|name| !name.is_empty())
+        .collect();
+    if names.is_empty() {
+        return Ok(CustomerField::all(custom_fields));
+    }
+
+    names.into_iter()
+        .map(|name| CustomerField::from_name(name, custom_fields)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("there is no field {}", name))))
+        .collect()
+}
+
+/// Writes a header row of field labels and a row per customer. Fields are
+/// quoted as RFC 4180 asks, only when they hold a comma, quote or line
+/// break, and lines end with CRLF.
+pub fn export<'a>(customers: impl IntoIterator<Item = &'a Customer>, columns: &[CustomerField]) -> Result<String, Error> {
+    let mut writer = csv::WriterBuilder::new()
+        .terminator(csv::Terminator::CRLF)
+        .quote_style(csv::QuoteStyle::Necessary)
+        .from_writer(Vec::new());
+
+    writer.write_record(columns.iter().map(|field| field.label()))?;
+    for customer in customers {
+        writer.write_record(columns.iter().map(|field| customer.get_field(field)))?;
+    }
+    let bytes = writer.into_inner().map_err(|e| Error::other(e.to_string()))?;
+
+    String::from_utf8(bytes).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv_import::CsvFile;
+    use crate::custom_field::CustomFieldType;
+    use crate::customer::{PhoneLabel, PhoneNumber};
+    use crate::test_dir::TestDir;
+    use std::fs;
+
+    fn custom_fields() -> Vec<CustomFieldDef> {
+        vec![
+            CustomFieldDef { name: "Account".to_string(), field_type: CustomFieldType::Text, required: false, choices: Vec::new() },
+            CustomFieldDef { name: "Tier".to_string(), field_type: CustomFieldType::Choice, required: false,
+                             choices: vec!["Gold".to_string(), "Silver".to_string()] },
+        ]
+    }
+
+    #[test]
+    fn export_then_import_round_trips() {
+        let custom_fields = custom_fields();
+        let mut awkward = Customer::new();
+        awkward.set_company_name("Smith, Jones & \"Partners\"".to_string());
+        awkward.set_contact_name("Ann Lee".to_string());
+        awkward.phones = vec![
+            PhoneNumber { label: PhoneLabel::Office, number: "07 3333 4444".to_string(), primary: false },
+            PhoneNumber { label: PhoneLabel::Mobile, number: "0412 345 678".to_string(), primary: true },
+        ];
+        awkward.emails = vec!["ann@example.com".to_string(), "accounts@example.com".to_string()];
+        awkward.address.street = "Level 2\nRear, 12 Main St".to_string();
+        awkward.custom_fields.insert("Account".to_string(), "A-1, \"key\"".to_string());
+        awkward.custom_fields.insert("Tier".to_string(), "Gold".to_string());
+        let mut plain = Customer::new();
+        plain.set_company_name("Plain Co".to_string());
+        let originals = vec![awkward, plain];
+
+        let columns = columns(&[], &custom_fields).unwrap();
+        let text = export(&originals, &columns).unwrap();
+        assert!(text.ends_with("\r\n"));
+        assert!(text.contains("\"Smith, Jones & \"\"Partners\"\"\""));
+        // Only fields that need it are quoted
+        assert!(text.contains("\r\nPlain Co,"));
+
+        let dir = TestDir::new("csv_export");
+        let path = dir.join("export.csv");
+        fs::write(&path, &text).unwrap();
+        let file = CsvFile::read(&path, None, None).unwrap();
+        assert_eq!(file.guess_mapping(&custom_fields), columns.iter().cloned().map(Some).collect::<Vec<_>>());
+        let (imported, errors) = file.to_customers(&file.guess_mapping(&custom_fields), &custom_fields);
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        assert_eq!(imported.len(), originals.len());
+        for (original, imported) in originals.iter().zip(imported.iter()) {
+            for field in columns.iter() {
+                assert_eq!(imported.get_field(field), original.get_field(field), "{} changed", field.label());
+            }
+        }
+    }
+
+    #[test]
+    fn columns_follow_the_names_given() {
+        let custom_fields = custom_fields();
+        let names = vec!["company, phones".to_string(), "Tier".to_string()];
+        assert_eq!(columns(&names, &custom_fields).unwrap(),
+                   [CustomerField::CompanyName, CustomerField::PhoneNumbers, CustomerField::Custom("Tier".to_string())]);
+        assert!(columns(&["nope".to_string()], &custom_fields).is_err());
+    }
+}
@@ -0,0 +1,406 @@
+use crate::custom_field::CustomFieldDef;
+use crate::customer::{Customer, CustomerField, PhoneLabel, PhoneNumber};
+use encoding_rs::{Encoding, UTF_8, WINDOWS_1252};
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+/// Delimiters tried when none is given, in order of preference.
+const DELIMITERS: [u8; 4] = [b',', b';', b'\t', b'|'];
+/// Encodings offered when the detected one is wrong.
+const ENCODINGS: [&str; 4] = ["UTF-8", "windows-1252", "ISO-8859-15", "UTF-16LE"];
+
+/// A CSV file read into memory, ready to be mapped onto customers.
+#[derive(Debug)]
+pub struct CsvFile {
+    /// The header names, or `Column 1`, `Column 2`... without a header row
+    pub headers: Vec<String>,
+    pub has_header: bool,
+    /// Each row with the line it starts on
+    pub rows: Vec<(u64, Vec<String>)>,
+    pub delimiter: u8,
+    pub encoding: &'static str,
+}
+
+/// A row that could not be imported.
+#[derive(Debug, Clone)]
+pub struct RowError {
+    pub line: u64,
+    pub message: String,
+}
+
+impl Display for RowError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl CsvFile {
+    /// Reads `path`, detecting the encoding and delimiter unless they are
+    /// given. A byte order mark decides the encoding, otherwise UTF-8 is
+    /// tried before Windows-1252, which is what spreadsheets tend to write.
+    pub fn read(path: &Path, delimiter: Option<u8>, encoding: Option<&str>) -> Result<CsvFile, Error> {
+        let bytes = fs::read(path)?;
+        let encoding = match encoding {
+            Some(label) => Encoding::for_label(label.as_bytes())
+                .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("unknown encoding {}", label)))?,
+            None => match Encoding::for_bom(&bytes) {
+                Some((encoding, _)) => encoding,
+                None if std::str::from_utf8(&bytes).is_ok() => UTF_8,
+                None => WINDOWS_1252,
+            },
+        };
+        let (text, _, malformed) = encoding.decode(&bytes);
+        if malformed {
+            log::info!("{} has bytes that are not valid {}, they were replaced", path.display(), encoding.name());
+        }
+
+        let delimiter = delimiter.unwrap_or_else(|| detect_delimiter(&text));
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(delimiter)
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(text.as_bytes());
+
+        let mut rows = Vec::new();
+        let (mut line, mut counted) = (1, 0);
+        for record in reader.records() {
+            let record = record.map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+            // The reader's own position lags one byte behind on CRLF line
+            // endings, sitting on the \n. A record never starts with \n, so
+            // counting the byte at the position as well gives the right line.
+            let end = record.position().map(|p| p.byte() as usize + 1).unwrap_or(counted).min(text.len());
+            line += text.as_bytes()[counted..end].iter().filter(|b| **b == b'\n').count() as u64;
+            counted = end;
+            let cells: Vec<String> = record.iter().map(|cell| cell.trim().to_string()).collect();
+            if cells.iter().all(|cell| cell.is_empty()) {
+                continue;
+            }
+            rows.push((line, cells));
+        }
+
+        let has_header = rows.first().map(|(_, cells)| is_header(cells)).unwrap_or(false);
+        let width = rows.iter().map(|(_, cells)| cells.len()).max().unwrap_or(0);
+        let mut headers: Vec<String> = if has_header { rows.remove(0).1 } else { Vec::new() };
+        for i in headers.len()..width {
+            headers.push(format!("Column {}", i + 1));
+        }
+
+        Ok(CsvFile { headers, has_header, rows, delimiter, encoding: encoding.name() })
+    }
+
+    /// A field for each column, guessed from the header names.
+    pub fn guess_mapping(&self, custom_fields: &[CustomFieldDef]) -> Vec<Option<CustomerField>> {
+        self.headers.iter().map(|header| field_for_header(header, custom_fields)).collect()
+    }
+
+    /// Builds a customer from every row, rows failing validation are
+    /// returned as errors instead.
+    pub fn to_customers(&self, mapping: &[Option<CustomerField>], custom_fields: &[CustomFieldDef])
+                        -> (Vec<Customer>, Vec<RowError>) {
+        let mut customers = Vec::new();
+        let mut errors = Vec::new();
+        for (line, cells) in self.rows.iter() {
+            match self.to_customer(cells, mapping, custom_fields) {
+                Ok(customer) => customers.push(customer),
+                Err(message) => errors.push(RowError { line: *line, message }),
+            }
+        }
+        (customers, errors)
+    }
+
+    fn to_customer(&self, cells: &[String], mapping: &[Option<CustomerField>], custom_fields: &[CustomFieldDef])
+                   -> Result<Customer, String> {
+        let mut customer = Customer::new();
+        for (i, field) in mapping.iter().enumerate() {
+            let (field, value) = match (field, cells.get(i)) {
+                (Some(field), Some(value)) if !value.is_empty() => (field, value),
+                _ => continue,
+            };
+            match field {
+                // Several columns can hold numbers, e.g. Phone, Mobile and Fax
+                CustomerField::PhoneNumbers => {
+                    if value.contains(':') || value.contains(',') {
+                        let mut parsed = Customer::new();
+                        parsed.set_phone_numbers(value.clone());
+                        customer.phones.extend(parsed.phones);
+                    } else {
+                        customer.phones.push(PhoneNumber::new(phone_label(&self.headers[i]), value.clone()));
+                    }
+                },
+                CustomerField::Emails => {
+                    for email in value.split([',', ';']).map(|e| e.trim()).filter(|e| !e.is_empty()) {
+                        if !email.contains('@') {
+                            return Err(format!("{} is not an email address", email));
+                        }
+                        customer.emails.push(email.to_string());
+                    }
+                },
+                CustomerField::Custom(name) => {
+                    let value = match custom_fields.iter().find(|def| def.name == *name) {
+                        Some(def) => def.validate(value)?,
+                        None => value.clone(),
+                    };
+                    customer.set_field(field, value);
+                },
+                _ => customer.set_field(field, value.clone()),
+            }
+        }
+
+        for def in custom_fields.iter().filter(|def| def.required) {
+            if !customer.custom_fields.contains_key(&def.name) {
+                return Err(format!("{} is required", def.name));
+            }
+        }
+        if customer.name.is_empty() && customer.contact_name.is_none() && customer.phones.is_empty()
+            && customer.emails.is_empty() {
+            return Err("no name, phone number or email".to_string());
+        }
+        customer.ensure_primary();
+
+        Ok(customer)
+    }
+}
+
+/// A CSV import being set up: the file as read so far and the field each
+/// column goes to.
+#[derive(Debug)]
+pub struct CsvImport {
+    pub path: PathBuf,
+    pub file: CsvFile,
+    pub mapping: Vec<Option<CustomerField>>,
+}
+
+impl CsvImport {
+    pub fn open(path: PathBuf, delimiter: Option<u8>, encoding: Option<&str>,
+                custom_fields: &[CustomFieldDef]) -> Result<CsvImport, Error> {
+        let file = CsvFile::read(&path, delimiter, encoding)?;
+        let mapping = file.guess_mapping(custom_fields);
+
+        Ok(CsvImport { path, file, mapping })
+    }
+
+    /// Reads the file again with another delimiter or encoding, the mapping
+    /// is guessed again when the columns changed.
+    pub fn reread(&mut self, delimiter: u8, encoding: &str, custom_fields: &[CustomFieldDef]) -> Result<(), Error> {
+        let file = CsvFile::read(&self.path, Some(delimiter), Some(encoding))?;
+        if file.headers != self.file.headers {
+            self.mapping = file.guess_mapping(custom_fields);
+        }
+        self.file = file;
+
+        Ok(())
+    }
+
+    /// Reads the file with the next delimiter in `DELIMITERS`.
+    pub fn next_delimiter(&mut self, custom_fields: &[CustomFieldDef]) -> Result<(), Error> {
+        let index = DELIMITERS.iter().position(|d| *d == self.file.delimiter).map(|i| i + 1).unwrap_or(0);
+        let encoding = self.file.encoding;
+        self.reread(DELIMITERS[index % DELIMITERS.len()], encoding, custom_fields)
+    }
+
+    /// Reads the file with the next encoding in `ENCODINGS`.
+    pub fn next_encoding(&mut self, custom_fields: &[CustomFieldDef]) -> Result<(), Error> {
+        let index = ENCODINGS.iter().position(|e| e.eq_ignore_ascii_case(self.file.encoding)).map(|i| i + 1).unwrap_or(0);
+        self.reread(self.file.delimiter, ENCODINGS[index % ENCODINGS.len()], custom_fields)
+    }
+
+    /// Moves a column on to the next field, or back with `forward` false,
+    /// ignoring the column sits before the first field.
+    pub fn cycle_field(&mut self, column: usize, forward: bool, custom_fields: &[CustomFieldDef]) {
+        let mut choices: Vec<Option<CustomerField>> = vec![None];
+        choices.extend(CustomerField::all(custom_fields).into_iter().map(Some));
+        if let Some(current) = self.mapping.get_mut(column) {
+            let index = choices.iter().position(|c| c == current).unwrap_or(0);
+            let next = if forward { index + 1 } else { index + choices.len() - 1 };
+            *current = choices[next % choices.len()].clone();
+        }
+    }
+
+    /// Maps the column named `header`, or numbered from 1, to `field`.
+    pub fn set_field(&mut self, column: &str, field: Option<CustomerField>) -> Result<(), Error> {
+        let index = match column.trim().parse::<usize>() {
+            Ok(number) if number >= 1 && number <= self.file.headers.len() => number - 1,
+            _ => self.file.headers.iter().position(|h| h.eq_ignore_ascii_case(column.trim()))
+                .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("there is no column {}", column)))?,
+        };
+        self.mapping[index] = field;
+
+        Ok(())
+    }
+
+    pub fn describe(&self) -> String {
+        format!("encoding {}, delimiter {}, {}", self.file.encoding, delimiter_name(self.file.delimiter),
+                if self.file.has_header { "header row" } else { "no header row" })
+    }
+
+    /// One line per column, `Header -> Field (first value)`.
+    pub fn mapping_rows(&self) -> Vec<String> {
+        self.file.headers.iter().enumerate()
+            .map(|(i, header)| {
+                let field = self.mapping[i].as_ref().map(|f| f.label()).unwrap_or("(ignored)");
+                let sample = self.file.rows.first().and_then(|(_, cells)| cells.get(i)).map(|s| s.as_str()).unwrap_or("");
+                format!("{}. {} -> {}  (e.g. {})", i + 1, header, field, sample)
+            })
+            .collect()
+    }
+
+    pub fn to_customers(&self, custom_fields: &[CustomFieldDef]) -> (Vec<Customer>, Vec<RowError>) {
+        self.file.to_customers(&self.mapping, custom_fields)
+    }
+}
+
+/// The delimiter splitting the first line into the most columns, a comma
+/// when there is none.
+fn detect_delimiter(text: &str) -> u8 {
+    let first_line = text.lines().next().unwrap_or("");
+    let mut best = (DELIMITERS[0], 0);
+    for delimiter in DELIMITERS {
+        let count = first_line.matches(delimiter as char).count();
+        if count > best.1 {
+            best = (delimiter, count);
+        }
+    }
+    best.0
+}
+
+/// A first row naming a known field, or holding no digits at all, is taken
+/// to be a header.
+fn is_header(cells: &[String]) -> bool {
+    cells.iter().any(|cell| field_for_header(cell, &[]).is_some())
+        || cells.iter().all(|cell| !cell.chars().any(|c| c.is_ascii_digit()))
+}
+
+/// The field a column header most likely holds.
+pub fn field_for_header(header: &str, custom_fields: &[CustomFieldDef]) -> Option<CustomerField> {
+    if let Some(field) = CustomerField::from_name(header, custom_fields) {
+        return Some(field);
+    }
+    let header = header.trim().to_lowercase();
+    let field = match header.as_str() {
+        "organisation" | "organization" | "business" | "business name" | "customer" | "account" | "account name" => CustomerField::CompanyName,
+        "contact person" | "full name" | "person" | "first name" => CustomerField::ContactName,
+        "telephone" | "tel" | "mobile" | "cell" | "fax" | "phone number" | "work phone" => CustomerField::PhoneNumbers,
+        "e-mail" | "email address" | "mail" => CustomerField::Emails,
+        "address" | "street address" | "address 1" => CustomerField::Street,
+        "city" | "town" | "locality" => CustomerField::Suburb,
+        "region" | "province" | "county" => CustomerField::State,
+        "zip" | "zip code" | "post code" | "postal code" => CustomerField::Postcode,
+        _ => return None,
+    };
+    Some(field)
+}
+
+/// The label for a number from a phone column, taken from its header.
+fn phone_label(header: &str) -> PhoneLabel {
+    let header = header.to_lowercase();
+    if header.contains("mobile") || header.contains("cell") {
+        PhoneLabel::Mobile
+    } else if header.contains("fax") {
+        PhoneLabel::Fax
+    } else {
+        PhoneLabel::Office
+    }
+}
+
+/// A delimiter given on the command line or at the prompt, `tab` for a tab.
+pub fn parse_delimiter(text: &str) -> Result<u8, Error> {
+    match text {
+        "tab" | "\\t" => Ok(b'\t'),
+        _ if text.len() == 1 && text.is_ascii() => Ok(text.as_bytes()[0]),
+        _ => Err(Error::new(ErrorKind::InvalidInput, format!("{} is not a single character delimiter", text))),
+    }
+}
+
+/// How the delimiter is shown to the user.
+pub fn delimiter_name(delimiter: u8) -> String {
+    match delimiter {
+        b'\t' => "tab".to_string(),
+        d => (d as char).to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    /// Writes `bytes` to a file of its own and reads it back.
+    fn read(name: &str, bytes: &[u8], delimiter: Option<u8>, encoding: Option<&str>) -> Result<CsvFile, Error> {
+        let dir = TestDir::new(&format!("csv_{}", name));
+        let path = dir.join("import.csv");
+        fs::write(&path, bytes).unwrap();
+        CsvFile::read(&path, delimiter, encoding)
+    }
+
+    fn customers(file: &CsvFile) -> (Vec<Customer>, Vec<RowError>) {
+        file.to_customers(&file.guess_mapping(&[]), &[])
+    }
+
+    #[test]
+    fn quoted_fields_keep_delimiters_quotes_and_line_breaks() {
+        let text = "Company,Contact,Phone,Email\r\n\
+                    \"Smith, Jones & Co\",\"Ann \"\"Annie\"\" Lee\",07 3333 4444,ann@example.com\r\n\
+                    \"Two\r\nLines Pty Ltd\",Bob,,\r\n\
+                    Plain Co,,,not-an-email\r\n";
+        let file = read("quoted", text.as_bytes(), None, None).unwrap();
+        assert!(file.has_header);
+        assert_eq!(file.delimiter, b',');
+
+        let (customers, errors) = customers(&file);
+        assert_eq!(customers.len(), 2);
+        assert_eq!(customers[0].name, "Smith, Jones & Co");
+        assert_eq!(customers[0].contact_name.as_deref(), Some("Ann \"Annie\" Lee"));
+        assert_eq!(customers[0].phones, vec![PhoneNumber { label: PhoneLabel::Office, number: "07 3333 4444".to_string(), primary: true }]);
+        assert_eq!(customers[1].name, "Two\r\nLines Pty Ltd");
+        // The line break inside the quotes still counts towards the line numbers
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line, 5);
+    }
+
+    #[test]
+    fn semicolon_delimiter_is_detected() {
+        let text = "Company;Phone;Mobile\nAcme, Inc;07 3333 4444;0412 345 678\n";
+        let file = read("semicolon", text.as_bytes(), None, None).unwrap();
+        assert_eq!(file.delimiter, b';');
+        assert_eq!(file.headers, ["Company", "Phone", "Mobile"]);
+
+        let (customers, errors) = customers(&file);
+        assert!(errors.is_empty());
+        assert_eq!(customers[0].name, "Acme, Inc");
+        assert_eq!(customers[0].phones.iter().map(|p| p.label.clone()).collect::<Vec<_>>(),
+                   [PhoneLabel::Office, PhoneLabel::Mobile]);
+    }
+
+    #[test]
+    fn given_delimiter_wins_over_detection() {
+        let text = "Company|Contact\nA;B|C,D\n";
+        let file = read("given", text.as_bytes(), Some(parse_delimiter("|").unwrap()), None).unwrap();
+        assert_eq!(file.rows[0].1, ["A;B", "C,D"]);
+        assert_eq!(parse_delimiter("tab").unwrap(), b'\t');
+        assert!(parse_delimiter("ab").is_err());
+    }
+
+    #[test]
+    fn windows_1252_is_detected() {
+        // "Café Müller" and a Euro sign as Windows-1252 writes them
+        let bytes = b"Company,Contact\nCaf\xe9 M\xfcller,\x80 Desk\n";
+        let file = read("cp1252", bytes, None, None).unwrap();
+        assert_eq!(file.encoding, "windows-1252");
+        assert_eq!(file.rows[0].1, ["Café Müller", "€ Desk"]);
+
+        // The same bytes read as UTF-8 only get replacement characters
+        let file = read("cp1252_as_utf8", bytes, None, Some("utf-8")).unwrap();
+        assert_eq!(file.rows[0].1[0], "Caf\u{fffd} M\u{fffd}ller");
+    }
+
+    #[test]
+    fn byte_order_mark_decides_the_encoding() {
+        let mut bytes = vec![0xff, 0xfe];
+        bytes.extend("Company\nÜber GmbH\n".encode_utf16().flat_map(|unit| unit.to_le_bytes()));
+        let file = read("utf16", &bytes, None, None).unwrap();
+        assert_eq!(file.encoding, "UTF-16LE");
+        assert_eq!(file.rows[0].1, ["Über GmbH"]);
+    }
+}
@@ -0,0 +1,138 @@
+use chrono::NaiveDate;
+use serde::{Serialize, Deserialize};
+use std::fmt::{self, Display, Formatter};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CustomFieldType {
+    Text,
+    Number,
+    Date,
+    Bool,
+    Choice,
+}
+
+impl Display for CustomFieldType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            CustomFieldType::Text => write!(f, "text"),
+            CustomFieldType::Number => write!(f, "number"),
+            CustomFieldType::Date => write!(f, "date YYYY-MM-DD"),
+            CustomFieldType::Bool => write!(f, "yes/no"),
+            CustomFieldType::Choice => write!(f, "choice"),
+        }
+    }
+}
+
+/// A custom field declared in config.toml, e.g.
+///
+/// ```toml
+/// [[custom_fields]]
+/// name = "Contract expiry"
+/// type = "date"
+/// required = false
+/// ```
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CustomFieldDef {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub field_type: CustomFieldType,
+    #[serde(default)]
+    pub required: bool,
+    /// The allowed values of a `choice` field
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub choices: Vec<String>,
+}
+
+impl CustomFieldDef {
+    /// Catches a definition that could never be filled in, checked when the
+    /// config is loaded.
+    pub fn check(&self) -> Result<(), String> {
+        if self.field_type == CustomFieldType::Choice && self.choices.iter().all(|c| c.trim().is_empty()) {
+            return Err(format!("custom field {} is a choice but lists no choices", self.name));
+        }
+
+        Ok(())
+    }
+
+    pub fn hint(&self) -> String {
+        let mut hint = match self.field_type {
+            CustomFieldType::Choice => self.choices.join("/"),
+            field_type => field_type.to_string(),
+        };
+        if self.required {
+            hint.push_str(", required");
+        }
+        hint
+    }
+
+    /// Checks `value` against the field type and returns it in its canonical
+    /// form, an empty string means the field is unset.
+    pub fn validate(&self, value: &str) -> Result<String, String> {
+        let value = value.trim();
+        if value.is_empty() {
+            if self.required {
+                return Err(format!("{} is required", self.name));
+            }
+            return Ok(String::new());
+        }
+
+        match self.field_type {
+            CustomFieldType::Text => Ok(value.to_string()),
+            // NaN and infinity parse as floats but are no use as a value
+            CustomFieldType::Number => value.parse::<f64>().ok()
+                .filter(|n| n.is_finite())
+                .map(|_| value.to_string())
+                .ok_or_else(|| format!("{} must be a number", self.name)),
+            CustomFieldType::Date => NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .map(|d| d.format("%Y-%m-%d").to_string())
+                .map_err(|_| format!("{} must be a date in the form YYYY-MM-DD", self.name)),
+            CustomFieldType::Bool => match value.to_lowercase().as_str() {
+                "y" | "yes" | "true" | "1" => Ok("yes".to_string()),
+                "n" | "no" | "false" | "0" => Ok("no".to_string()),
+                _ => Err(format!("{} must be yes or no", self.name)),
+            },
+            CustomFieldType::Choice => self.choices.iter()
+                .find(|c| c.eq_ignore_ascii_case(value))
+                .cloned()
+                .ok_or_else(|| format!("{} must be one of {}", self.name, self.choices.join(", "))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(field_type: CustomFieldType, choices: &[&str]) -> CustomFieldDef {
+        CustomFieldDef {
+            name: "Field".to_string(),
+            field_type,
+            required: false,
+            choices: choices.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn numbers_must_be_finite() {
+        let number = field(CustomFieldType::Number, &[]);
+        assert_eq!(number.validate(" 12.5 "), Ok("12.5".to_string()));
+        for value in ["NaN", "inf", "-infinity", "ten"] {
+            assert!(number.validate(value).is_err(), "{} was accepted", value);
+        }
+    }
+
+    #[test]
+    fn choices_match_ignoring_case() {
+        let choice = field(CustomFieldType::Choice, &["Gold", "Silver"]);
+        assert_eq!(choice.validate("gold"), Ok("Gold".to_string()));
+        assert!(choice.validate("Bronze").is_err());
+    }
+
+    #[test]
+    fn choice_without_choices_is_rejected() {
+        assert!(field(CustomFieldType::Choice, &[]).check().is_err());
+        assert!(field(CustomFieldType::Choice, &["Gold"]).check().is_ok());
+        assert!(field(CustomFieldType::Text, &[]).check().is_ok());
+    }
+}
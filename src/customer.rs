@@ -1,32 +1,466 @@
-use fake::faker::phone_number::en::PhoneNumber;
+use fake::faker::phone_number::en::PhoneNumber as FakePhoneNumber;
 use fake::faker::name::en::Name;
+use fake::faker::internet::en::SafeEmail;
+use fake::faker::address::en::{BuildingNumber, StreetName, CityName, StateAbbr, PostCode};
 use fake::Fake;
 use std::path::PathBuf;
 use std::io::Error;
-use std::fs::{self,File};
+use std::fs;
+use crate::contacts_file::ContactsFile;
+use crate::crypto::FileKey;
 use serde::{Serialize, Deserialize};
 use std::fmt::{self, Display, Formatter};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use crate::custom_field::CustomFieldDef;
+use uuid::Uuid;
+use chrono::{DateTime, Datelike, Days, Local, NaiveDate, NaiveTime, TimeDelta, TimeZone, Utc, Weekday};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(from = "String", into = "String")]
+pub enum PhoneLabel {
+    Office,
+    Mobile,
+    Fax,
+    Custom(String),
+}
+
+impl From<String> for PhoneLabel {
+    fn from(label: String) -> Self {
+        match label.trim().to_lowercase().as_str() {
+            "" | "office" => PhoneLabel::Office,
+            "mobile" => PhoneLabel::Mobile,
+            "fax" => PhoneLabel::Fax,
+            _ => PhoneLabel::Custom(label.trim().to_string()),
+        }
+    }
+}
+
+impl From<PhoneLabel> for String {
+    fn from(label: PhoneLabel) -> Self {
+        label.to_string()
+    }
+}
+
+impl Display for PhoneLabel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PhoneLabel::Office => write!(f, "office"),
+            PhoneLabel::Mobile => write!(f, "mobile"),
+            PhoneLabel::Fax => write!(f, "fax"),
+            PhoneLabel::Custom(label) => write!(f, "{}", label),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PhoneNumber {
+    pub label: PhoneLabel,
+    pub number: String,
+    #[serde(default)]
+    pub primary: bool,
+}
+
+impl PhoneNumber {
+    pub fn new(label: PhoneLabel, number: String) -> PhoneNumber {
+        PhoneNumber {
+            label,
+            number,
+            primary: false,
+        }
+    }
+}
+
+impl Display for PhoneNumber {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.label, self.number)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Address {
+    pub street: String,
+    pub suburb: String,
+    pub state: String,
+    pub postcode: String,
+    pub country: String,
+}
+
+impl Address {
+    pub fn is_empty(&self) -> bool {
+        self.street.is_empty() && self.suburb.is_empty() && self.state.is_empty()
+            && self.postcode.is_empty() && self.country.is_empty()
+    }
+}
+
+impl Display for Address {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let parts = [&self.street, &self.suburb, &self.state, &self.postcode, &self.country];
+        let parts: Vec<&str> = parts.iter().map(|p| p.as_str()).filter(|p| !p.is_empty()).collect();
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum NoteKind {
+    Call,
+    Meeting,
+    Email,
+    Note,
+}
+
+impl NoteKind {
+    pub fn parse(kind: &str) -> Option<NoteKind> {
+        match kind.trim().to_lowercase().as_str() {
+            "call" => Some(NoteKind::Call),
+            "meeting" => Some(NoteKind::Meeting),
+            "email" => Some(NoteKind::Email),
+            "note" => Some(NoteKind::Note),
+            _ => None,
+        }
+    }
+}
+
+impl Display for NoteKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            NoteKind::Call => write!(f, "call"),
+            NoteKind::Meeting => write!(f, "meeting"),
+            NoteKind::Email => write!(f, "email"),
+            NoteKind::Note => write!(f, "note"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Note {
+    pub timestamp: DateTime<Utc>,
+    pub kind: NoteKind,
+    pub text: String,
+}
+
+impl Note {
+    pub fn new(kind: NoteKind, text: String) -> Note {
+        Note {
+            timestamp: Utc::now(),
+            kind,
+            text,
+        }
+    }
+}
+
+impl Display for Note {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} [{}] {}",
+               self.timestamp.with_timezone(&Local).format("%Y-%m-%d %H:%M"),
+               self.kind,
+               self.text)
+    }
+}
+
+/// How urgent a task is, the most urgent sorts first.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    High,
+    Normal,
+    Low,
+}
+
+impl Priority {
+    pub fn parse(priority: &str) -> Option<Priority> {
+        match priority.trim().to_lowercase().as_str() {
+            "high" | "h" => Some(Priority::High),
+            "normal" | "n" | "" => Some(Priority::Normal),
+            "low" | "l" => Some(Priority::Low),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Priority {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Priority::High => write!(f, "high"),
+            Priority::Normal => write!(f, "normal"),
+            Priority::Low => write!(f, "low"),
+        }
+    }
+}
+
+/// Something to follow up with the customer by a due time, e.g. a call back.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Task {
+    pub id: Uuid,
+    pub due: DateTime<Utc>,
+    pub priority: Priority,
+    pub text: String,
+    #[serde(default)]
+    pub done: bool,
+}
+
+impl Task {
+    pub fn new(text: String, due: DateTime<Utc>, priority: Priority) -> Task {
+        Task {
+            id: Uuid::new_v4(),
+            due,
+            priority,
+            text,
+            done: false,
+        }
+    }
+
+    pub fn is_overdue(&self, now: DateTime<Utc>) -> bool {
+        !self.done && self.due < now
+    }
+
+    /// Parses a due time as typed, `2024-07-01 14:30`, `2024-07-01`,
+    /// `today 16:00`, `tomorrow`, `+3d` or `friday`, in local time. A day
+    /// without a time is due at 9am, a weekday is the next one to come.
+    pub fn parse_due(due: &str, now: DateTime<Local>) -> Option<DateTime<Utc>> {
+        let due = due.trim().to_lowercase();
+        let (day, time) = match due.split_once(' ') {
+            Some((day, time)) => (day, Some(NaiveTime::parse_from_str(time.trim(), "%H:%M").ok()?)),
+            None => (due.as_str(), None),
+        };
+        let today = now.date_naive();
+        let date = match day {
+            "today" => today,
+            "tomorrow" => today.succ_opt()?,
+            _ => match day.strip_prefix('+').and_then(|d| d.strip_suffix('d')) {
+                // Too far out to be a date is as good as unparseable
+                Some(days) => today.checked_add_signed(TimeDelta::try_days(days.parse().ok()?)?)?,
+                None => match day.parse::<Weekday>() {
+                    Ok(weekday) => {
+                        let ahead = (weekday.num_days_from_monday() + 6 - today.weekday().num_days_from_monday()) % 7 + 1;
+                        today.checked_add_days(Days::new(ahead.into()))?
+                    },
+                    Err(_) => NaiveDate::parse_from_str(day, "%Y-%m-%d").ok()?,
+                },
+            },
+        };
+        let time = time.unwrap_or_else(|| NaiveTime::from_hms_opt(9, 0, 0).unwrap_or_default());
+
+        Local.from_local_datetime(&date.and_time(time)).earliest().map(|d| d.with_timezone(&Utc))
+    }
+}
+
+impl Display for Task {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} [{}] {}{}",
+               self.due.with_timezone(&Local).format("%Y-%m-%d %H:%M"),
+               self.priority,
+               self.text,
+               if self.done { " (done)" } else { "" })
+    }
+}
+
+/// The fields the add/edit flow steps through, in prompt order. Custom
+/// fields declared in config.toml follow the built in ones.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CustomerField {
+    CompanyName,
+    ContactName,
+    PhoneNumbers,
+    Emails,
+    Street,
+    Suburb,
+    State,
+    Postcode,
+    Country,
+    Custom(String),
+}
+
+impl CustomerField {
+    pub const BUILT_IN: [CustomerField; 9] = [
+        CustomerField::CompanyName,
+        CustomerField::ContactName,
+        CustomerField::PhoneNumbers,
+        CustomerField::Emails,
+        CustomerField::Street,
+        CustomerField::Suburb,
+        CustomerField::State,
+        CustomerField::Postcode,
+        CustomerField::Country,
+    ];
+
+    /// Every field, built in ones first, then the custom fields.
+    pub fn all(custom_fields: &[CustomFieldDef]) -> Vec<CustomerField> {
+        CustomerField::BUILT_IN.iter().cloned()
+            .chain(custom_fields.iter().map(|def| CustomerField::Custom(def.name.clone())))
+            .collect()
+    }
+    /// The field called `name`, by its label or a short name like `phones`,
+    /// ignoring case.
+    pub fn from_name(name: &str, custom_fields: &[CustomFieldDef]) -> Option<CustomerField> {
+        let name = name.trim().to_lowercase();
+        let field = match name.as_str() {
+            "company" | "name" => CustomerField::CompanyName,
+            "contact" => CustomerField::ContactName,
+            "phone" | "phones" => CustomerField::PhoneNumbers,
+            "email" => CustomerField::Emails,
+            _ => return CustomerField::all(custom_fields).into_iter()
+                .find(|field| field.label().to_lowercase() == name),
+        };
+        Some(field)
+    }
+    pub fn first() -> CustomerField {
+        CustomerField::BUILT_IN[0].clone()
+    }
+    pub fn next(&self, custom_fields: &[CustomFieldDef]) -> Option<CustomerField> {
+        let all = CustomerField::all(custom_fields);
+        let index = all.iter().position(|f| f == self)?;
+        all.get(index + 1).cloned()
+    }
+    pub fn label(&self) -> &str {
+        match self {
+            CustomerField::CompanyName => "Company name",
+            CustomerField::ContactName => "Contact name",
+            CustomerField::PhoneNumbers => "Phone numbers",
+            CustomerField::Emails => "Emails",
+            CustomerField::Street => "Street",
+            CustomerField::Suburb => "Suburb",
+            CustomerField::State => "State",
+            CustomerField::Postcode => "Postcode",
+            CustomerField::Country => "Country",
+            CustomerField::Custom(name) => name,
+        }
+    }
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            CustomerField::PhoneNumbers => Some("*label: number, ..."),
+            CustomerField::Emails => Some("comma separated"),
+            _ => None,
+        }
+    }
+}
+
+const SAMPLE_TAGS: [&str; 5] = ["vip", "supplier", "overdue", "north", "south"];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Customer {
+    // Records written before ids existed are given one on load
+    #[serde(default = "Uuid::new_v4")]
+    pub id: Uuid,
+    #[serde(default = "Utc::now")]
+    pub created_at: DateTime<Utc>,
+    #[serde(default = "Utc::now")]
+    pub updated_at: DateTime<Utc>,
+    /// Set while the customer is in the trash
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime<Utc>>,
     pub name: String,
     pub contact_name: Option<String>,
-    pub phone: Option<String>,
+    /// The company record this person belongs to, `name` holds a copy of
+    /// its name so search and exports see it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub company_id: Option<Uuid>,
+    /// Favourites are listed before everyone else
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pinned: bool,
+    #[serde(default)]
+    pub phones: Vec<PhoneNumber>,
+    #[serde(default)]
+    pub emails: Vec<String>,
+    #[serde(default, skip_serializing_if = "Address::is_empty")]
+    pub address: Address,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub notes: Vec<Note>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tasks: Vec<Task>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub tags: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub custom_fields: BTreeMap<String, String>,
 }
 
 impl Customer {
     pub fn new() -> Customer {
+        let now = Utc::now();
         Customer {
+            id: Uuid::new_v4(),
+            created_at: now,
+            updated_at: now,
+            deleted_at: None,
             name: String::new(),
             contact_name: None,
-            phone: None,
+            company_id: None,
+            pinned: false,
+            phones: Vec::new(),
+            emails: Vec::new(),
+            address: Address::default(),
+            notes: Vec::new(),
+            tasks: Vec::new(),
+            tags: BTreeSet::new(),
+            custom_fields: BTreeMap::new(),
         }
     }
-    pub fn load_customers(file_path: PathBuf) -> Result<Vec<Customer>, Error> {
-        let file = File::open(file_path)?;
+    pub fn load_customers(file_path: PathBuf, key: Option<&FileKey>) -> Result<Vec<Customer>, Error> {
+        let contacts = ContactsFile::load(&file_path, key)?;
+        log::info!("Loaded {} (v{}, saved {} by v{})", file_path.display(), contacts.version,
+                   contacts.metadata.saved_at, contacts.metadata.app_version);
+        let mut customers = contacts.customers;
+        for mut customer in contacts.trash {
+            customer.deleted_at.get_or_insert_with(Utc::now);
+            customers.push(customer);
+        }
 
-        let mut customers: Vec<Customer> = serde_json::from_reader(file)?;
+        let mut ids = HashSet::new();
+        for customer in customers.iter_mut() {
+            customer.ensure_primary();
+            // A hand edited file may contain copied records, keep ids unique
+            if !ids.insert(customer.id) {
+                customer.id = Uuid::new_v4();
+                ids.insert(customer.id);
+            }
+        }
 
+        Customer::sort(&mut customers);
+        Ok(customers)
+    }
+    /// A record for a company itself rather than a person, it has no contact
+    /// name and belongs to no other company.
+    pub fn is_company(&self) -> bool {
+        self.company_id.is_none() && self.get_contact_name().trim().is_empty()
+    }
+    /// Links each person not yet linked to the company record of the same
+    /// name, ignoring case, adding the company when there is none. Flat
+    /// company and contact rows become a company with its people this way.
+    /// Returns the ids of the records added or changed, the trash is left
+    /// alone.
+    pub fn link_companies(customers: &mut Vec<Customer>) -> Vec<Uuid> {
+        let mut companies: HashMap<String, (Uuid, String)> = HashMap::new();
+        for company in customers.iter().filter(|c| c.deleted_at.is_none() && c.is_company()) {
+            companies.entry(company.name.trim().to_lowercase()).or_insert((company.id, company.name.clone()));
+        }
+
+        let mut added = Vec::new();
+        let mut changed = Vec::new();
+        for person in customers.iter_mut().filter(|c| c.deleted_at.is_none() && c.company_id.is_none()) {
+            if person.is_company() || person.name.trim().is_empty() {
+                continue;
+            }
+            let (id, name) = companies.entry(person.name.trim().to_lowercase())
+                .or_insert_with(|| {
+                    let mut company = Customer::new();
+                    company.name = person.name.trim().to_string();
+                    company.created_at = person.created_at;
+                    let found = (company.id, company.name.clone());
+                    added.push(company);
+                    found
+                })
+                .clone();
+            person.company_id = Some(id);
+            person.name = name;
+            changed.push(person.id);
+        }
+
+        changed.extend(added.iter().map(|c| c.id));
+        customers.extend(added);
+        changed
+    }
+    /// Sorts by company then contact name, records without names go last.
+    pub fn sort(customers: &mut [Customer]) {
         customers.sort_by(|a, b| {
             let name_a = if a.name.is_empty() { String::from("\u{10FFFF}") } else { a.name.clone() };
             let name_b = if b.name.is_empty() { String::from("\u{10FFFF}") } else { b.name.clone() };
@@ -39,31 +473,60 @@ impl Customer {
                 name_order
             }
         });
-        Ok(customers)
     }
-    pub fn save_customers(customers: &[Customer], file_path: PathBuf) -> Result<(), Error> {
+    pub fn save_customers(customers: &[Customer], file_path: PathBuf, backups: usize, key: Option<&FileKey>) -> Result<(), Error> {
         log::info!("Saving customers to {}", file_path.display());
         // Ensure the directory exists
         if let Some(parent) = file_path.parent() {
-            fs::create_dir_all(parent).unwrap();
+            fs::create_dir_all(parent)?;
         }
 
-        // Open the file
-        let file = File::create(&file_path).unwrap();
-
         // Serialize the customers into the file
-        serde_json::to_writer_pretty(file, customers).unwrap();
-
-        Ok(())
-    }    
+        ContactsFile::save(customers, &file_path, backups, key)
+    }
     pub fn generate(n: usize) -> Vec<Customer> {
         (1..n).map(|_| Customer::sample()).collect()
     }
     pub fn sample() -> Customer {
+        let labels = [PhoneLabel::Office, PhoneLabel::Mobile, PhoneLabel::Fax];
+        let count = (1..labels.len() + 1).fake::<usize>();
+        let mut phones: Vec<PhoneNumber> = labels.into_iter()
+            .take(count)
+            .map(|label| PhoneNumber::new(label, FakePhoneNumber().fake::<String>()))
+            .collect();
+        phones[0].primary = true;
+
+        let now = Utc::now();
         Customer {
+            id: Uuid::new_v4(),
+            created_at: now,
+            updated_at: now,
+            deleted_at: None,
             name: Name().fake::<String>(),
             contact_name: Some(Name().fake::<String>()),
-            phone: Some(PhoneNumber().fake::<String>()),
+            company_id: None,
+            pinned: false,
+            phones,
+            emails: vec![SafeEmail().fake::<String>()],
+            address: Address {
+                street: format!("{} {}", BuildingNumber().fake::<String>(), StreetName().fake::<String>()),
+                suburb: CityName().fake::<String>(),
+                state: StateAbbr().fake::<String>(),
+                postcode: PostCode().fake::<String>(),
+                country: String::from("Australia"),
+            },
+            notes: Vec::new(),
+            tasks: Vec::new(),
+            tags: SAMPLE_TAGS.iter()
+                .filter(|_| (0..4).fake::<u8>() == 0)
+                .map(|t| t.to_string())
+                .collect(),
+            custom_fields: BTreeMap::new(),
+        }
+    }
+    pub fn ensure_primary(&mut self) {
+        if !self.phones.is_empty() && !self.phones.iter().any(|p| p.primary) {
+            self.phones[0].primary = true;
         }
     }
     pub fn set_company_name(&mut self, name: String) {
@@ -72,24 +535,246 @@ impl Customer {
     pub fn set_contact_name(&mut self, contact_name: String) {
         self.contact_name = Some(contact_name);
     }
-    pub fn set_phone_number(&mut self, phone: String) {
-        self.phone = Some(phone);
+    /// Parses a comma separated list of `label: number` entries, a leading
+    /// `*` marks the primary number, e.g. `*office: 07 3333 4444, mobile: 0400 111 222`.
+    /// Entries without a label are treated as office numbers.
+    pub fn set_phone_numbers(&mut self, phones: String) {
+        self.phones = phones.split(',')
+            .filter_map(|entry| {
+                let entry = entry.trim();
+                let (primary, entry) = match entry.strip_prefix('*') {
+                    Some(rest) => (true, rest.trim()),
+                    None => (false, entry),
+                };
+                let (label, number) = match entry.split_once(':') {
+                    Some((label, number)) => (PhoneLabel::from(label.to_string()), number.trim()),
+                    None => (PhoneLabel::Office, entry),
+                };
+                if number.is_empty() {
+                    return None;
+                }
+                Some(PhoneNumber { label, number: number.to_string(), primary })
+            })
+            .collect();
+
+        // Only one number may be primary, the first one marked wins
+        if let Some(first) = self.phones.iter().position(|p| p.primary) {
+            for (i, phone) in self.phones.iter_mut().enumerate() {
+                phone.primary = i == first;
+            }
+        }
+        self.ensure_primary();
     }
     pub fn get_company_name(&self) -> String {
         self.name.clone()
     }
     pub fn get_contact_name(&self) -> String {
-        self.contact_name.clone().unwrap_or(String::new())
+        self.contact_name.clone().unwrap_or_default()
+    }
+    pub fn get_phone_numbers(&self) -> String {
+        let mark_primary = self.phones.len() > 1;
+        self.phones.iter()
+            .map(|p| format!("{}{}", if mark_primary && p.primary { "*" } else { "" }, p))
+            .collect::<Vec<String>>()
+            .join(", ")
     }
-    pub fn get_phone_number(&self) -> String {
-        self.phone.clone().unwrap_or(String::new())
+    pub fn set_emails(&mut self, emails: String) {
+        self.emails = emails.split(',')
+            .map(|e| e.trim().to_string())
+            .filter(|e| !e.is_empty())
+            .collect();
+    }
+    pub fn get_emails(&self) -> String {
+        self.emails.join(", ")
+    }
+    pub fn get_field(&self, field: &CustomerField) -> String {
+        match field {
+            CustomerField::CompanyName => self.get_company_name(),
+            CustomerField::ContactName => self.get_contact_name(),
+            CustomerField::PhoneNumbers => self.get_phone_numbers(),
+            CustomerField::Emails => self.get_emails(),
+            CustomerField::Street => self.address.street.clone(),
+            CustomerField::Suburb => self.address.suburb.clone(),
+            CustomerField::State => self.address.state.clone(),
+            CustomerField::Postcode => self.address.postcode.clone(),
+            CustomerField::Country => self.address.country.clone(),
+            CustomerField::Custom(name) => self.custom_fields.get(name).cloned().unwrap_or_default(),
+        }
+    }
+    pub fn set_field(&mut self, field: &CustomerField, value: String) {
+        match field {
+            CustomerField::CompanyName => self.set_company_name(value),
+            CustomerField::ContactName => self.set_contact_name(value),
+            CustomerField::PhoneNumbers => self.set_phone_numbers(value),
+            CustomerField::Emails => self.set_emails(value),
+            CustomerField::Street => self.address.street = value.trim().to_string(),
+            CustomerField::Suburb => self.address.suburb = value.trim().to_string(),
+            CustomerField::State => self.address.state = value.trim().to_string(),
+            CustomerField::Postcode => self.address.postcode = value.trim().to_string(),
+            CustomerField::Country => self.address.country = value.trim().to_string(),
+            CustomerField::Custom(name) => {
+                if value.trim().is_empty() {
+                    self.custom_fields.remove(name);
+                } else {
+                    self.custom_fields.insert(name.clone(), value.trim().to_string());
+                }
+            }
+        }
+    }
+    /// Case insensitive match of `query` against every searchable field,
+    /// `query` is expected to already be lowercase.
+    pub fn matches(&self, query: &str) -> bool {
+        self.search_text().contains(query)
+    }
+    /// Every searchable value lowercased, one per line.
+    pub fn search_text(&self) -> String {
+        let mut text: Vec<String> = CustomerField::BUILT_IN.iter()
+            .filter(|field| **field != CustomerField::PhoneNumbers)
+            .map(|field| self.get_field(field))
+            .collect();
+        text.extend(self.phones.iter().map(|p| p.number.clone()));
+        text.extend(self.notes.iter().map(|n| n.text.clone()));
+        text.extend(self.tasks.iter().map(|t| t.text.clone()));
+        text.extend(self.custom_fields.values().cloned());
+        text.join("\n").to_lowercase()
+    }
+    /// Formats the customer for a list row `width` columns wide. Tags are
+    /// always shown, emails and the address are only appended when they fit.
+    pub fn row(&self, width: usize) -> String {
+        let mut row = self.to_string();
+        for tag in self.tags.iter() {
+            row.push_str(" #");
+            row.push_str(tag);
+        }
+        let extras = [
+            self.emails.first().cloned().unwrap_or_default(),
+            [self.address.suburb.as_str(), self.address.state.as_str()].iter()
+                .filter(|p| !p.is_empty())
+                .copied()
+                .collect::<Vec<&str>>()
+                .join(" "),
+        ];
+        for extra in extras.iter().filter(|e| !e.is_empty()) {
+            if row.chars().count() + extra.chars().count() + 3 <= width {
+                row.push_str(" - ");
+                row.push_str(extra);
+            }
+        }
+        row.chars().take(width).collect()
+    }
+    /// Normalizes a tag as typed by the user, `#VIP` and `vip` are the same tag.
+    pub fn parse_tag(tag: &str) -> Option<String> {
+        let tag = tag.trim().trim_start_matches('#').to_lowercase();
+        if tag.is_empty() {
+            None
+        } else {
+            Some(tag)
+        }
+    }
+    pub fn set_tags(&mut self, tags: String) {
+        self.tags = tags.split(|c: char| c.is_whitespace() || c == ',')
+            .filter_map(Customer::parse_tag)
+            .collect();
+    }
+    pub fn get_tags(&self) -> String {
+        self.tags.iter().cloned().collect::<Vec<String>>().join(" ")
+    }
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.contains(tag)
+    }
+    pub fn add_note(&mut self, kind: NoteKind, text: String) {
+        self.notes.push(Note::new(kind, text));
+    }
+    /// The interaction history, newest first.
+    pub fn history(&self) -> Vec<&Note> {
+        let mut notes: Vec<&Note> = self.notes.iter().collect();
+        notes.sort_by_key(|n| std::cmp::Reverse(n.timestamp));
+        notes
+    }
+    /// The tasks not done yet that are past due.
+    pub fn overdue_tasks(&self, now: DateTime<Utc>) -> usize {
+        self.tasks.iter().filter(|t| t.is_overdue(now)).count()
+    }
+    pub fn primary_phone(&self) -> Option<&PhoneNumber> {
+        self.phones.iter().find(|p| p.primary).or(self.phones.first())
     }
 }
 impl Display for Customer {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "{} - {} - {}", 
+        write!(f, "{} - {} - {}",
                if self.name.is_empty() { "(none)" } else { &self.name },
                self.contact_name.as_deref().unwrap_or("(none)"),
-               self.phone.as_deref().unwrap_or("(none)"))
+               self.primary_phone().map(|p| p.number.as_str()).unwrap_or("(none)"))?;
+        if self.phones.len() > 1 {
+            write!(f, " (+{})", self.phones.len() - 1)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Wednesday 3 July 2024, 10am local time.
+    fn now() -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 7, 3, 10, 0, 0).unwrap()
+    }
+
+    fn due(text: &str) -> Option<String> {
+        Task::parse_due(text, now()).map(|d| d.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string())
+    }
+
+    #[test]
+    fn due_times_parse() {
+        assert_eq!(due("2024-08-01 14:30").as_deref(), Some("2024-08-01 14:30"));
+        assert_eq!(due("2024-08-01").as_deref(), Some("2024-08-01 09:00"));
+        assert_eq!(due("Today 16:00").as_deref(), Some("2024-07-03 16:00"));
+        assert_eq!(due("tomorrow").as_deref(), Some("2024-07-04 09:00"));
+        assert_eq!(due("+3d").as_deref(), Some("2024-07-06 09:00"));
+        assert_eq!(due("+10d 08:15").as_deref(), Some("2024-07-13 08:15"));
+    }
+
+    #[test]
+    fn weekday_is_the_next_one() {
+        assert_eq!(due("friday").as_deref(), Some("2024-07-05 09:00"));
+        assert_eq!(due("mon 13:00").as_deref(), Some("2024-07-08 13:00"));
+        // Today's weekday means a week from now
+        assert_eq!(due("wednesday").as_deref(), Some("2024-07-10 09:00"));
+    }
+
+    #[test]
+    fn bad_or_out_of_range_due_times_are_refused() {
+        assert_eq!(due("+99999999999d"), None);
+        assert_eq!(due(&format!("+{}d", i64::MAX)), None);
+        assert_eq!(due("+3x"), None);
+        assert_eq!(due("2024-13-01"), None);
+        assert_eq!(due("tomorrow 25:00"), None);
+        assert_eq!(due("someday"), None);
+    }
+
+    #[test]
+    fn copied_records_get_their_own_id_on_load() {
+        let dir = crate::test_dir::TestDir::new("customer_ids");
+        let path = dir.join("contacts.json");
+        let mut original = Customer::new();
+        original.set_company_name("Acme".to_string());
+        let mut copy = original.clone();
+        copy.set_company_name("Acme copy".to_string());
+        let mut trashed = original.clone();
+        trashed.set_company_name("Acme trashed".to_string());
+        trashed.deleted_at = Some(Utc::now());
+        Customer::save_customers(&[original.clone(), copy, trashed], path.clone(), 0, None).unwrap();
+
+        let loaded = Customer::load_customers(path.clone(), None).unwrap();
+        let ids: HashSet<Uuid> = loaded.iter().map(|c| c.id).collect();
+        assert_eq!(ids.len(), 3);
+        // The first record keeps the id, links to it still hold
+        assert_eq!(loaded.iter().find(|c| c.id == original.id).unwrap().name, "Acme");
+
+        // Saved again the new ids stay put
+        Customer::save_customers(&loaded, path.clone(), 0, None).unwrap();
+        let reloaded: HashSet<Uuid> = Customer::load_customers(path, None).unwrap().iter().map(|c| c.id).collect();
+        assert_eq!(reloaded, ids);
     }
 }
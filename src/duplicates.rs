@@ -0,0 +1,299 @@
+use crate::custom_field::CustomFieldDef;
+use crate::customer::{Customer, CustomerField};
+use std::collections::HashMap;
+
+/// Numbers with fewer digits are extensions or short codes, too likely to
+/// be shared by unrelated customers.
+const MIN_PHONE_DIGITS: usize = 6;
+/// Digits compared at the end of a number, enough to tell local numbers
+/// apart while ignoring a country code or trunk prefix.
+const PHONE_DIGITS: usize = 9;
+
+/// The company name lowercased with everything but letters and digits
+/// dropped, so `ACME Pty. Ltd.` and `Acme Pty Ltd` match.
+pub fn normalize_name(name: &str) -> String {
+    name.chars().filter(|c| c.is_alphanumeric()).flat_map(|c| c.to_lowercase()).collect()
+}
+
+/// The last digits of a phone number, so `+61 7 3123 4567` and
+/// `(07) 3123-4567` match. `None` for numbers too short to compare.
+pub fn normalize_phone(number: &str) -> Option<String> {
+    let digits: Vec<char> = number.chars().filter(|c| c.is_ascii_digit()).collect();
+    if digits.len() < MIN_PHONE_DIGITS {
+        return None;
+    }
+    Some(digits[digits.len().saturating_sub(PHONE_DIGITS)..].iter().collect())
+}
+
+fn keys(customer: &Customer) -> Vec<String> {
+    let mut keys = Vec::new();
+    // People carry their company's name, only companies match on it
+    let name = normalize_name(&customer.name);
+    if !name.is_empty() && customer.company_id.is_none() {
+        keys.push(format!("name:{}", name));
+    }
+    keys.extend(customer.phones.iter().filter_map(|p| normalize_phone(&p.number)).map(|p| format!("tel:{}", p)));
+    keys
+}
+
+/// Groups of customers sharing a normalized name or phone number, as
+/// indexes into `customers`. Sharing is transitive, A and C are in one
+/// group when both share something with B.
+pub fn find_groups(customers: &[Customer]) -> Vec<Vec<usize>> {
+    // Union-find over the customers, joined through the first customer
+    // seen with each key
+    let mut parent: Vec<usize> = (0..customers.len()).collect();
+    fn root(parent: &mut [usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
+        }
+        i
+    }
+
+    let mut first_with_key: HashMap<String, usize> = HashMap::new();
+    for (i, customer) in customers.iter().enumerate() {
+        for key in keys(customer) {
+            match first_with_key.get(&key) {
+                Some(&other) => {
+                    let (a, b) = (root(&mut parent, i), root(&mut parent, other));
+                    parent[a] = b;
+                },
+                None => {
+                    first_with_key.insert(key, i);
+                },
+            }
+        }
+    }
+
+    let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+    for i in 0..customers.len() {
+        groups.entry(root(&mut parent, i)).or_default().push(i);
+    }
+    let mut groups: Vec<Vec<usize>> = groups.into_values().filter(|g| g.len() > 1).collect();
+    for group in groups.iter_mut() {
+        // Oldest first, it survives the merge
+        group.sort_by_key(|i| customers[*i].created_at);
+    }
+    groups.sort_by_key(|g| customers[g[0]].name.to_lowercase());
+    groups
+}
+
+/// The values offered for one field when merging.
+#[derive(Debug, Clone)]
+pub struct FieldChoice {
+    pub field: CustomerField,
+    pub values: Vec<String>,
+    pub selected: usize,
+}
+
+/// A group of duplicates being merged. The first customer survives and
+/// absorbs the others, taking the value picked for each field.
+#[derive(Debug, Clone)]
+pub struct MergePlan {
+    pub customers: Vec<Customer>,
+    pub choices: Vec<FieldChoice>,
+}
+
+impl MergePlan {
+    pub fn new(customers: Vec<Customer>, custom_fields: &[CustomFieldDef]) -> MergePlan {
+        let choices = CustomerField::all(custom_fields).into_iter()
+            .filter_map(|field| {
+                let mut values: Vec<String> = Vec::new();
+                // Numbers and emails can be combined, offered first
+                match field {
+                    CustomerField::PhoneNumbers => values.push(combined_phones(&customers).get_phone_numbers()),
+                    CustomerField::Emails => values.push(combined_emails(&customers).join(", ")),
+                    _ => {},
+                }
+                for value in customers.iter().map(|c| c.get_field(&field)) {
+                    if !value.trim().is_empty() && !values.contains(&value) {
+                        values.push(value);
+                    }
+                }
+                values.retain(|v| !v.trim().is_empty());
+                if values.is_empty() {
+                    None
+                } else {
+                    Some(FieldChoice { field, values, selected: 0 })
+                }
+            })
+            .collect();
+
+        MergePlan { customers, choices }
+    }
+
+    /// Offers the next value, or the previous with `forward` false.
+    pub fn cycle(&mut self, row: usize, forward: bool) {
+        if let Some(choice) = self.choices.get_mut(row) {
+            let count = choice.values.len();
+            choice.selected = if forward { (choice.selected + 1) % count } else { (choice.selected + count - 1) % count };
+        }
+    }
+
+    /// One line per field, `Field: value (n of m)`.
+    pub fn rows(&self) -> Vec<String> {
+        self.choices.iter()
+            .map(|choice| {
+                let value = &choice.values[choice.selected];
+                if choice.values.len() > 1 {
+                    format!("{}: {}  ({} of {})", choice.field.label(), value, choice.selected + 1, choice.values.len())
+                } else {
+                    format!("{}: {}", choice.field.label(), value)
+                }
+            })
+            .collect()
+    }
+
+    /// The surviving record, with the values picked, every note, task and
+    /// tag of the group, and custom values only the others had.
+    pub fn merged(&self) -> Customer {
+        let mut survivor = self.customers[0].clone();
+        for other in self.customers.iter().skip(1) {
+            survivor.notes.extend(other.notes.iter().cloned());
+            survivor.tasks.extend(other.tasks.iter().cloned());
+            survivor.pinned |= other.pinned;
+            survivor.tags.extend(other.tags.iter().cloned());
+            for (name, value) in other.custom_fields.iter() {
+                survivor.custom_fields.entry(name.clone()).or_insert_with(|| value.clone());
+            }
+        }
+        survivor.notes.sort_by_key(|n| n.timestamp);
+        for choice in self.choices.iter() {
+            survivor.set_field(&choice.field, choice.values[choice.selected].clone());
+        }
+        survivor.ensure_primary();
+
+        survivor
+    }
+
+    /// The customers absorbed into the survivor.
+    pub fn absorbed(&self) -> impl Iterator<Item = &Customer> {
+        self.customers.iter().skip(1)
+    }
+}
+
+/// Every number of the group once, the survivor's primary stays primary.
+fn combined_phones(customers: &[Customer]) -> Customer {
+    let mut combined = Customer::new();
+    for phone in customers.iter().flat_map(|c| c.phones.iter()) {
+        let key = normalize_phone(&phone.number).unwrap_or_else(|| phone.number.trim().to_string());
+        if combined.phones.iter().any(|p| normalize_phone(&p.number).unwrap_or_else(|| p.number.trim().to_string()) == key) {
+            continue;
+        }
+        let mut phone = phone.clone();
+        phone.primary = phone.primary && !combined.phones.iter().any(|p| p.primary);
+        combined.phones.push(phone);
+    }
+    combined.ensure_primary();
+    combined
+}
+
+fn combined_emails(customers: &[Customer]) -> Vec<String> {
+    let mut emails: Vec<String> = Vec::new();
+    for email in customers.iter().flat_map(|c| c.emails.iter()) {
+        if !emails.iter().any(|e| e.eq_ignore_ascii_case(email)) {
+            emails.push(email.clone());
+        }
+    }
+    emails
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::custom_field::CustomFieldType;
+    use crate::customer::{Note, NoteKind, PhoneLabel, PhoneNumber, Priority, Task};
+    use chrono::{Duration, Utc};
+
+    fn company(name: &str, numbers: &[&str]) -> Customer {
+        let mut customer = Customer::new();
+        customer.set_company_name(name.to_string());
+        customer.phones = numbers.iter().map(|n| PhoneNumber::new(PhoneLabel::Office, n.to_string())).collect();
+        customer.ensure_primary();
+        customer
+    }
+
+    #[test]
+    fn names_and_numbers_normalize() {
+        assert_eq!(normalize_name("ACME Pty. Ltd."), normalize_name("Acme Pty Ltd"));
+        assert_eq!(normalize_phone("+61 7 3123 4567"), normalize_phone("(07) 3123-4567"));
+        assert_eq!(normalize_phone("ext 1234"), None);
+    }
+
+    #[test]
+    fn groups_are_transitive() {
+        // A shares a name with B, B a number with C, D stands alone
+        let customers = vec![
+            company("Acme", &[]),
+            company("ACME.", &["07 3123 4567"]),
+            company("Globex", &["+61 7 3123 4567"]),
+            company("Initech", &["07 5555 0000"]),
+        ];
+        let groups = find_groups(&customers);
+        assert_eq!(groups.len(), 1);
+        let mut group = groups[0].clone();
+        group.sort();
+        assert_eq!(group, [0, 1, 2]);
+    }
+
+    #[test]
+    fn short_numbers_do_not_match() {
+        let customers = vec![company("Acme", &["12345"]), company("Globex", &["12345"])];
+        assert!(find_groups(&customers).is_empty());
+    }
+
+    #[test]
+    fn people_do_not_match_on_company_name() {
+        let acme = company("Acme", &[]);
+        let mut ann = company("Acme", &[]);
+        ann.set_contact_name("Ann Lee".to_string());
+        ann.company_id = Some(acme.id);
+        let mut bob = ann.clone();
+        bob.id = uuid::Uuid::new_v4();
+        bob.set_contact_name("Bob Hill".to_string());
+        assert!(find_groups(&[acme, ann, bob]).is_empty());
+    }
+
+    #[test]
+    fn merge_keeps_the_survivor_and_combines_the_rest() {
+        let custom_fields = vec![
+            CustomFieldDef { name: "Account".to_string(), field_type: CustomFieldType::Text, required: false, choices: Vec::new() },
+            CustomFieldDef { name: "Region".to_string(), field_type: CustomFieldType::Text, required: false, choices: Vec::new() },
+        ];
+        let mut survivor = company("Acme", &["07 3123 4567", "0412 345 678"]);
+        survivor.phones[1].primary = true;
+        survivor.phones[0].primary = false;
+        survivor.created_at = Utc::now() - Duration::days(30);
+        survivor.notes.push(Note::new(NoteKind::Call, "first".to_string()));
+        survivor.tags.insert("vip".to_string());
+        survivor.custom_fields.insert("Account".to_string(), "A-1".to_string());
+
+        let mut other = company("ACME Pty Ltd", &["(07) 3123-4567", "07 5555 0000"]);
+        other.phones[1].primary = true;
+        other.phones[0].primary = false;
+        let mut note = Note::new(NoteKind::Meeting, "second".to_string());
+        note.timestamp = survivor.notes[0].timestamp - Duration::days(1);
+        other.notes.push(note);
+        other.tasks.push(Task::new("call back".to_string(), Utc::now(), Priority::High));
+        other.tags.insert("supplier".to_string());
+        other.custom_fields.insert("Account".to_string(), "B-2".to_string());
+        other.custom_fields.insert("Region".to_string(), "North".to_string());
+
+        let groups = find_groups(&[other.clone(), survivor.clone()]);
+        assert_eq!(groups, [vec![1, 0]]);
+
+        let merged = MergePlan::new(vec![survivor.clone(), other], &custom_fields).merged();
+        assert_eq!(merged.id, survivor.id);
+        assert_eq!(merged.name, "Acme");
+        // Each number once, the survivor's primary still primary
+        let numbers: Vec<(&str, bool)> = merged.phones.iter().map(|p| (p.number.as_str(), p.primary)).collect();
+        assert_eq!(numbers, [("07 3123 4567", false), ("0412 345 678", true), ("07 5555 0000", false)]);
+        let notes: Vec<&str> = merged.notes.iter().map(|n| n.text.as_str()).collect();
+        assert_eq!(notes, ["second", "first"]);
+        assert_eq!(merged.tasks.len(), 1);
+        assert!(merged.tags.contains("vip") && merged.tags.contains("supplier"));
+        assert_eq!(merged.custom_fields.get("Account").map(String::as_str), Some("A-1"));
+        assert_eq!(merged.custom_fields.get("Region").map(String::as_str), Some("North"));
+    }
+}
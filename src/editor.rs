@@ -2,79 +2,175 @@ use crate::colors::ColorScheme;
 use crate::line_buffer::LineBuffer;
 use crate::scroll_buffer::ScrollBuffer;
 use crate::status_line::StatusLine;
-use crate::utils::RawMode;
-use crate::customer::Customer;
+use crate::utils::{expand_path, RawMode};
+use crate::customer::{Customer, CustomerField, NoteKind, Priority, Task};
+use crate::list_view::ListView;
+use crate::custom_field::CustomFieldDef;
+use crate::storage::StorageKind;
+use crate::merge::Conflict;
+use crate::undo::{Change, UndoHistory};
+use crate::call_log::CallRecord;
+use uuid::Uuid;
+use chrono::{DateTime, Local, Utc};
+use std::cmp::Reverse;
 use crossterm::event::{read, poll, Event, KeyCode, KeyModifiers};
 use std::io;
-use std::path::PathBuf;
+use crate::vcard;
+use crate::csv_export;
+use crate::duplicates::MergePlan;
+use crate::grandstream::{self, AccountMap};
+use crate::csv_import::{self, CsvImport};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-#[derive(Debug, PartialEq)]
+/// Customers listed before the rest of a CSV preview is summed up.
+const PREVIEW_ROWS: usize = 10;
+/// Calls listed by the recent calls view.
+const RECENT_CALLS: usize = 100;
+/// How often the overdue count catches up with the clock while idle.
+const OVERDUE_REFRESH: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum EditorMode {
     Normal,
     SplashScreen,
-    AddCompanyName,
-    AddContactName,
-    AddPhoneNumber,
-    EditCompanyName,
-    EditContactName,
-    EditPhoneNumber,
+    Add(CustomerField),
+    Edit(CustomerField),
+    SelectPhoneNumber,
+    EditTags,
+    AddNoteKind,
+    AddNoteText,
+    AddTaskText,
+    AddTaskDue,
+    AddTaskPriority,
+    Agenda,
+    RecentCalls,
+    History,
+    SaveConflict,
+    MergeConflicts,
+    Trash,
+    QuitConfirm,
+    Passphrase,
+    Command,
+    CsvMapping,
+    CsvPreview,
+    Duplicates,
+    MergeDuplicates,
     Delete
 }
 pub struct Editor {
-    pub file_path: PathBuf,
+    pub file_path: Option<PathBuf>,  // The contacts store, the backend default when not given
+    pub backend: Option<StorageKind>,
     pub config_path: PathBuf,
     pub line_buffer: LineBuffer,     // The line buffer
     pub scroll_buffer: ScrollBuffer, // The scroll buffer
     pub status_line: StatusLine,     // The status line
+    pub list_view: ListView,         // The list shown by the non customer views
     pub mode: EditorMode,            // The editor mode
+    #[allow(dead_code)]
     pub color_scheme: ColorScheme,   // The color scheme
     temp_customer: Customer,         // The temporary customer
+    temp_note_kind: NoteKind,        // The kind of note being added
+    temp_task: Task,                 // The task being added
     no_splash: bool,
     sample_data: bool,
+    load_error: Option<String>,      // Why the contacts file could not be loaded
+    lock_warning: Option<String>,    // Set when another instance has the contacts open
+    conflicts: Vec<Conflict>,        // Records changed both here and on disk
+    history: UndoHistory,            // Undo and redo of customer changes
+    quit: bool,                      // Set to leave the event loop
+    last_activity: Instant,          // The last key press, for autosave
+    overdue_refreshed: Instant,      // When the overdue count was last worked out
+    autosave_mark: usize,            // Edits counted at the last autosave attempt
+    csv_import: Option<CsvImport>,   // The CSV file being mapped and previewed
+    duplicate_groups: Vec<Vec<Uuid>>, // The groups listed by the duplicates view
+    merge_plan: Option<MergePlan>,   // The duplicates being merged
+    agenda: Vec<(Uuid, Uuid)>,       // The customer and task of each agenda row
+    recent_calls: Vec<Uuid>,         // The customer of each recent calls row
+    purge_pending: Option<Uuid>,     // The trash entry waiting for y/n before it is purged
     _raw_mode: RawMode,              // The raw mode
 }
 
 impl Editor {
-    pub fn new(file_path: PathBuf, config_path: PathBuf, no_splash: bool, sample_data: bool) -> Result<Editor, std::io::Error> {
+    pub fn new(file_path: Option<PathBuf>, backend: Option<StorageKind>, config_path: PathBuf, no_splash: bool, sample_data: bool) -> Result<Editor, std::io::Error> {
         let color_scheme = ColorScheme::new();
         let line_buffer = LineBuffer::new("Query: ".to_string(), color_scheme.clone());
         let scroll_buffer = ScrollBuffer::new(color_scheme.clone())?;
         let status_line = StatusLine::new(color_scheme.clone())?;
+        let list_view = ListView::new(color_scheme.clone())?;
 
         let _raw_mode = RawMode::new()?;
 
         Ok(Editor {
             file_path,
+            backend,
             config_path,
             line_buffer,
             scroll_buffer,
             status_line,
+            list_view,
             mode: EditorMode::SplashScreen,
             color_scheme,
             temp_customer: Customer::new(),
+            temp_note_kind: NoteKind::Call,
+            temp_task: Task::new(String::new(), Utc::now(), Priority::Normal),
             no_splash,
             sample_data,
+            load_error: None,
+            lock_warning: None,
+            conflicts: Vec::new(),
+            history: UndoHistory::new(),
+            quit: false,
+            last_activity: Instant::now(),
+            overdue_refreshed: Instant::now(),
+            autosave_mark: 0,
+            csv_import: None,
+            duplicate_groups: Vec::new(),
+            merge_plan: None,
+            agenda: Vec::new(),
+            recent_calls: Vec::new(),
+            purge_pending: None,
             _raw_mode
         })
     }
 
     pub fn run(&mut self) -> io::Result<()> {
         loop {
-            if poll(std::time::Duration::from_millis(500))? {
+            if poll(Duration::from_millis(500))? {
                 if let Event::Key(event) = read()? {
+                    self.last_activity = Instant::now();
+                    let shortcut = event.code == KeyCode::Esc || (event.modifiers.contains(KeyModifiers::CONTROL)
+                        && !matches!(event.code, KeyCode::Char('q') | KeyCode::Char('x')));
+                    if self.mode == EditorMode::Passphrase && shortcut {
+                        // Nothing to work on until the contacts are unlocked
+                        continue;
+                    }
                     match event.code {
-                        KeyCode::Char('q') if event.modifiers.contains(KeyModifiers::CONTROL) => {
-                            log::info!("Exiting editor loop, received CTRL+Q");
-                            if ! self.sample_data {
-                                self.save()?;
-                            }
-                            break;
+                        KeyCode::Char('q') if event.modifiers.contains(KeyModifiers::CONTROL) => { self.quit()?; },
+                        KeyCode::Char('x') if event.modifiers.contains(KeyModifiers::CONTROL) => {
+                            log::info!("Discarding any unsaved changes, received CTRL+X");
+                            self.quit = true;
                         },
-                        KeyCode::Char('s') if event.modifiers.contains(KeyModifiers::CONTROL) => { if !self.sample_data { self.save()?; } },
+                        KeyCode::Char('s') if event.modifiers.contains(KeyModifiers::CONTROL) => { self.save()?; },
                         KeyCode::Char('c') if event.modifiers.contains(KeyModifiers::CONTROL) => { self.call_customer()?; },
                         KeyCode::Char('a') if event.modifiers.contains(KeyModifiers::CONTROL) => { self.add_customer()?; },
                         KeyCode::Char('e') if event.modifiers.contains(KeyModifiers::CONTROL) => { self.edit_customer()?; },
                         KeyCode::Char('d') if event.modifiers.contains(KeyModifiers::CONTROL) => { self.delete_customer()?; },
+                        KeyCode::Char('t') if event.modifiers.contains(KeyModifiers::CONTROL) => { self.edit_tags()?; },
+                        KeyCode::Char('z') if event.modifiers.contains(KeyModifiers::CONTROL) => { self.undo()?; },
+                        KeyCode::Char('y') if event.modifiers.contains(KeyModifiers::CONTROL) => { self.redo()?; },
+                        KeyCode::Char('n') if event.modifiers.contains(KeyModifiers::CONTROL) => { self.add_note()?; },
+                        KeyCode::Char('l') if event.modifiers.contains(KeyModifiers::CONTROL) => { self.show_history()?; },
+                        KeyCode::Char('r') if event.modifiers.contains(KeyModifiers::CONTROL) => { self.show_trash()?; },
+                        KeyCode::Char('k') if event.modifiers.contains(KeyModifiers::CONTROL) => { self.set_mode(EditorMode::Command)?; },
+                        KeyCode::Char('g') if event.modifiers.contains(KeyModifiers::CONTROL) => { self.set_mode(EditorMode::Duplicates)?; },
+                        KeyCode::Char('p') if event.modifiers.contains(KeyModifiers::CONTROL) => { self.add_contact()?; },
+                        KeyCode::Char('o') if event.modifiers.contains(KeyModifiers::CONTROL) => { self.add_task()?; },
+                        KeyCode::Char('u') if event.modifiers.contains(KeyModifiers::CONTROL) => { self.set_mode(EditorMode::Agenda)?; },
+                        KeyCode::Char('w') if event.modifiers.contains(KeyModifiers::CONTROL) => { self.set_mode(EditorMode::RecentCalls)?; },
+                        KeyCode::Char('f') if event.modifiers.contains(KeyModifiers::CONTROL) => { self.toggle_pinned()?; },
+                        KeyCode::Char('b') if event.modifiers.contains(KeyModifiers::CONTROL) => { self.toggle_favourites_only()?; },
                         KeyCode::Char(' ') => { 
                             if self.mode == EditorMode::SplashScreen {
                                 self.set_mode(EditorMode::Normal)?;
@@ -83,6 +179,7 @@ impl Editor {
                         }
                         KeyCode::Esc => { self.set_mode(EditorMode::Normal)?; },
                         KeyCode::Enter => { self.enter()?; },
+                        KeyCode::Tab => { self.toggle_expanded()?; },
                         KeyCode::Char(c) => { self.add_key(c)?; },
                         KeyCode::Insert => { self.toggle_insert()?; },
                         KeyCode::Left => { self.move_left()?; },
@@ -97,6 +194,15 @@ impl Editor {
                     }
                 }
             }
+            if self.quit {
+                log::info!("Exiting editor loop");
+                break;
+            }
+            self.autosave()?;
+            // Tasks fall due while the editor sits idle
+            if self.overdue_refreshed.elapsed() >= OVERDUE_REFRESH {
+                self.refresh_overdue()?;
+            }
         }
 
         Ok(())
@@ -104,8 +210,18 @@ impl Editor {
 
     pub fn set_mode(&mut self, mode: EditorMode) -> io::Result<()> {
         log::info!("Setting mode to {:?}", mode);
+        if self.mode == EditorMode::Passphrase && mode != EditorMode::Passphrase {
+            self.line_buffer.set_masked(false)?;
+        }
+        if !matches!(mode, EditorMode::CsvMapping | EditorMode::CsvPreview) {
+            self.csv_import = None;
+        }
+        if mode != EditorMode::MergeDuplicates {
+            self.merge_plan = None;
+        }
+        self.purge_pending = None;
         self.mode = mode;
-        match self.mode {
+        match self.mode.clone() {
             EditorMode::SplashScreen => {
                 self.scroll_buffer.splash_screen()?;
                 self.line_buffer.set_prompt("".to_string())?;
@@ -114,50 +230,218 @@ impl Editor {
             },
             EditorMode::Normal => {
                 self.line_buffer.set_prompt("Query: ".to_string())?;
-                self.status_line.set_message("Normal Mode".to_string())?;
+                let message = self.load_error.clone()
+                    .or_else(|| self.lock_warning.clone())
+                    .unwrap_or_else(|| "Normal Mode".to_string());
+                self.status_line.set_message(message)?;
                 self.line_buffer.clear()?;
                 self.filter()?;
             },
-            EditorMode::AddCompanyName => {
-                self.line_buffer.set_prompt("Company name: ".to_string())?;
-                self.status_line.set_message("Add Company".to_string())?;
-                self.line_buffer.clear()?;
-            },
-            EditorMode::AddContactName => {
-                self.line_buffer.set_prompt("Contact name: ".to_string())?;
-                self.status_line.set_message("Add Contact".to_string())?;
+            EditorMode::Add(field) => {
+                self.line_buffer.set_prompt(format!("{}: ", field.label()))?;
+                self.status_line.set_message(self.field_message("Add", &field))?;
                 self.line_buffer.clear()?;
             },
-            EditorMode::AddPhoneNumber => {
-                self.line_buffer.set_prompt("Phone number: ".to_string())?;
-                self.status_line.set_message("Add Phone Number".to_string())?;
-                self.line_buffer.clear()?;
+            EditorMode::Edit(field) => {
+                self.line_buffer.set_buffer(self.temp_customer.get_field(&field))?;
+                self.line_buffer.set_prompt(format!("{}: ", field.label()))?;
+                self.status_line.set_message(self.field_message("Edit", &field))?;
             },
-            EditorMode::EditCompanyName => {
-                log::info!("{:?}", self.scroll_buffer.get_selected_customer());
+            EditorMode::SelectPhoneNumber => {
+                let mut choices = Vec::new();
+                let mut primary = 1;
                 if let Some(customer) = self.scroll_buffer.get_selected_customer() {
-                    log::info!("Setting buffer to {}", customer.get_company_name());
-                    self.line_buffer.set_buffer(customer.get_company_name())?;
+                    for (i, phone) in customer.phones.iter().enumerate() {
+                        if phone.primary {
+                            primary = i + 1;
+                        }
+                        choices.push(format!("{}) {}", i + 1, phone));
+                    }
                 }
-                self.line_buffer.set_prompt("Company name: ".to_string())?;
-                self.status_line.set_message("Edit Company".to_string())?;
+                self.line_buffer.set_prompt(format!("Dial which number (1-{}): ", choices.len()))?;
+                self.line_buffer.set_buffer(primary.to_string())?;
+                self.status_line.set_message(choices.join("  "))?;
             },
-            EditorMode::EditContactName => {
+            EditorMode::EditTags => {
                 if let Some(customer) = self.scroll_buffer.get_selected_customer() {
-                    self.line_buffer.set_buffer(customer.get_contact_name())?;
+                    self.line_buffer.set_buffer(customer.get_tags())?;
                 }
-                self.line_buffer.set_prompt("Contact name: ".to_string())?;
-                self.status_line.set_message("Edit Contact".to_string())?;
+                self.line_buffer.set_prompt("Tags: ".to_string())?;
+                self.status_line.set_message("Edit Tags (space separated)".to_string())?;
             },
-            EditorMode::EditPhoneNumber => {
-                if let Some(customer) = self.scroll_buffer.get_selected_customer() {
-                    self.line_buffer.set_buffer(customer.get_phone_number())?;
-                }
-                self.line_buffer.set_prompt("Phone number: ".to_string())?;
-                self.status_line.set_message("Edit Phone Number".to_string())?;
+            EditorMode::AddNoteKind => {
+                self.line_buffer.set_prompt("Note kind (call/meeting/email/note): ".to_string())?;
+                self.line_buffer.set_buffer(self.temp_note_kind.to_string())?;
+                self.status_line.set_message("Add Note".to_string())?;
+            },
+            EditorMode::AddNoteText => {
+                self.line_buffer.set_prompt(format!("{} note: ", self.temp_note_kind))?;
+                self.line_buffer.clear()?;
+                self.status_line.set_message("Add Note".to_string())?;
+            },
+            EditorMode::AddTaskText => {
+                let customer = self.scroll_buffer.get_selected_customer().map(|c| c.to_string()).unwrap_or_default();
+                self.line_buffer.set_prompt("Task: ".to_string())?;
+                self.line_buffer.clear()?;
+                self.status_line.set_message(format!("Add Task for {}", customer))?;
+            },
+            EditorMode::AddTaskDue => {
+                self.line_buffer.set_prompt("Due (YYYY-MM-DD HH:MM, today, tomorrow, +3d, friday): ".to_string())?;
+                self.line_buffer.set_buffer("tomorrow".to_string())?;
+                self.status_line.set_message("Add Task".to_string())?;
+            },
+            EditorMode::AddTaskPriority => {
+                self.line_buffer.set_prompt("Priority (high/normal/low): ".to_string())?;
+                self.line_buffer.set_buffer(self.temp_task.priority.to_string())?;
+                self.status_line.set_message("Add Task".to_string())?;
+            },
+            EditorMode::Agenda => {
+                let (title, items) = self.agenda_items();
+                self.line_buffer.set_prompt("".to_string())?;
+                self.line_buffer.clear()?;
+                self.list_view.set_items(title, items)?;
+                self.status_line.set_message("Enter to go to the customer, (d)one, Esc to return".to_string())?;
+            },
+            EditorMode::History => {
+                let (title, items) = match self.scroll_buffer.get_selected_customer() {
+                    Some(customer) => {
+                        // Notes and calls interleaved, newest first
+                        let mut entries: Vec<(DateTime<Utc>, String)> = customer.history().iter()
+                            .map(|n| (n.timestamp, n.to_string()))
+                            .collect();
+                        entries.extend(self.scroll_buffer.get_calls(customer.id).iter().map(|c| (c.timestamp, c.to_string())));
+                        entries.sort_by_key(|(timestamp, _)| Reverse(*timestamp));
+                        (format!("History for {}", customer), entries.into_iter().map(|(_, entry)| entry).collect())
+                    },
+                    None => (String::new(), Vec::new()),
+                };
+                self.line_buffer.set_prompt("".to_string())?;
+                self.line_buffer.clear()?;
+                self.list_view.set_items(title, items)?;
+                self.status_line.set_message("History (Esc to return)".to_string())?;
+            },
+            EditorMode::RecentCalls => {
+                let calls = self.scroll_buffer.get_recent_calls(RECENT_CALLS);
+                let items = calls.iter()
+                    .map(|call| {
+                        let customer = self.scroll_buffer.get_customer(call.customer_id)
+                            .map(|c| c.to_string())
+                            .unwrap_or_else(|| "(deleted)".to_string());
+                        // The customer first, a failed call's error can be long
+                        format!("{}  - {}", customer, call)
+                    })
+                    .collect();
+                self.recent_calls = calls.iter().map(|c| c.customer_id).collect();
+                self.line_buffer.set_prompt("".to_string())?;
+                self.line_buffer.clear()?;
+                self.list_view.set_items("Recent calls".to_string(), items)?;
+                self.status_line.set_message("Enter to go to the customer, Esc to return".to_string())?;
+            },
+            EditorMode::SaveConflict => {
+                self.line_buffer.set_prompt("Contacts changed on disk. (r)eload, (o)verwrite, (m)erge or (c)ancel: ".to_string())?;
+                self.line_buffer.clear()?;
+                self.status_line.set_message("Save Conflict".to_string())?;
+            },
+            EditorMode::MergeConflicts => {
+                let items = self.conflicts.iter()
+                    .map(|c| format!("{} - {}", if c.name.is_empty() { "(none)" } else { &c.name }, c.description))
+                    .collect();
+                self.line_buffer.set_prompt("".to_string())?;
+                self.line_buffer.clear()?;
+                self.list_view.set_items(format!("Merge conflicts ({})", self.conflicts.len()), items)?;
+                self.status_line.set_message("Enter to use the other version, Esc when done".to_string())?;
+            },
+            EditorMode::Trash => {
+                let items = self.scroll_buffer.get_trash().iter()
+                    .map(|c| format!("{} - deleted {}", c, c.deleted_at.unwrap_or_default()
+                        .with_timezone(&Local).format("%Y-%m-%d %H:%M")))
+                    .collect();
+                let days = self.scroll_buffer.get_config().trash_days;
+                let title = if days > 0 {
+                    format!("Trash (purged after {} days)", days)
+                } else {
+                    "Trash".to_string()
+                };
+                self.line_buffer.set_prompt("".to_string())?;
+                self.line_buffer.clear()?;
+                self.list_view.set_items(title, items)?;
+                self.status_line.set_message("(r)estore, (p)urge, Esc to return".to_string())?;
+            },
+            EditorMode::Duplicates => {
+                let groups = self.scroll_buffer.find_duplicates();
+                let items = groups.iter()
+                    .map(|group| group.iter().map(|c| c.to_string()).collect::<Vec<String>>().join("  /  "))
+                    .collect();
+                self.duplicate_groups = groups.iter().map(|group| group.iter().map(|c| c.id).collect()).collect();
+                self.line_buffer.set_prompt("".to_string())?;
+                self.line_buffer.clear()?;
+                self.list_view.set_items(format!("Possible duplicates ({} groups)", self.duplicate_groups.len()), items)?;
+                self.status_line.set_message("Enter to merge the selected group, Esc to return".to_string())?;
+            },
+            EditorMode::MergeDuplicates => {
+                let (title, items) = self.merge_items();
+                self.line_buffer.set_prompt("".to_string())?;
+                self.line_buffer.clear()?;
+                self.list_view.set_items(title, items)?;
+                self.status_line.set_message("Left/Right pick the value to keep, Enter to merge, Esc to cancel".to_string())?;
+            },
+            EditorMode::QuitConfirm => {
+                self.line_buffer.set_prompt("Unsaved changes. (s)ave, (d)iscard or (c)ancel: ".to_string())?;
+                self.line_buffer.clear()?;
+                self.status_line.set_message("Quit".to_string())?;
+            },
+            EditorMode::Passphrase => {
+                self.line_buffer.set_prompt("Passphrase: ".to_string())?;
+                self.line_buffer.set_masked(true)?;
+                self.line_buffer.clear()?;
+                self.status_line.set_message("The contacts file is encrypted (Ctrl+Q to quit)".to_string())?;
+            },
+            EditorMode::Command => {
+                self.line_buffer.set_prompt("Command: ".to_string())?;
+                self.line_buffer.clear()?;
+                self.status_line.set_message(
+                    "import-vcard|import-grandstream <file>, import-csv [-d delimiter] [-e encoding] <file>, \
+                     export-vcard|export-csv|export-grandstream [selected|all] <file>".to_string())?;
+            },
+            EditorMode::CsvMapping => {
+                let (title, items) = self.csv_mapping_items();
+                self.line_buffer.set_prompt("".to_string())?;
+                self.line_buffer.clear()?;
+                self.list_view.set_items(title, items)?;
+                self.status_line.set_message(
+                    "Left/Right change the field, (d)elimiter, (e)ncoding, Enter to preview, Esc to cancel".to_string())?;
+            },
+            EditorMode::CsvPreview => {
+                let custom_fields = self.scroll_buffer.get_config().custom_fields.clone();
+                let (title, items, count) = match &self.csv_import {
+                    Some(import) => {
+                        let (customers, errors) = import.to_customers(&custom_fields);
+                        let mut items: Vec<String> = customers.iter().take(PREVIEW_ROWS).map(|c| c.to_string()).collect();
+                        if customers.len() > PREVIEW_ROWS {
+                            items.push(format!("... and {} more", customers.len() - PREVIEW_ROWS));
+                        }
+                        items.extend(errors.iter().map(|e| format!("Failed {}", e)));
+                        (format!("Preview of {}: {} customers, {} rows failed", import.path.display(), customers.len(), errors.len()),
+                         items, customers.len())
+                    },
+                    None => (String::new(), Vec::new(), 0),
+                };
+                self.line_buffer.set_prompt(format!("Import {} customers (y/n): ", count))?;
+                self.line_buffer.clear()?;
+                self.list_view.set_items(title, items)?;
+                self.status_line.set_message("(y) to import, (n) to change the mapping, Esc to cancel".to_string())?;
             },
             EditorMode::Delete => {
-                self.line_buffer.set_prompt("Delete (y/n): ".to_string())?;
+                let prompt = match self.scroll_buffer.get_selected_customer() {
+                    Some(customer) if customer.is_company() => match self.scroll_buffer.people_of(customer.id).len() {
+                        0 => format!("Delete {}? (y/n): ", customer.name),
+                        1 => format!("Delete {} and its 1 person? (y/n): ", customer.name),
+                        n => format!("Delete {} and its {} people? (y/n): ", customer.name, n),
+                    },
+                    Some(customer) => format!("Delete {}? (y/n): ", customer.get_contact_name()),
+                    None => "Delete (y/n): ".to_string(),
+                };
+                self.line_buffer.set_prompt(prompt)?;
                 self.status_line.set_message("DeleteMode".to_string())?;
             }
         }
@@ -165,40 +449,222 @@ impl Editor {
         Ok(())
     }
 
+    fn field_message(&self, action: &str, field: &CustomerField) -> String {
+        let hint = match field {
+            CustomerField::Custom(name) => self.custom_field_def(name).map(|def| def.hint()),
+            _ => field.hint().map(|hint| hint.to_string()),
+        };
+        match hint {
+            Some(hint) => format!("{} {} ({})", action, field.label(), hint),
+            None => format!("{} {}", action, field.label()),
+        }
+    }
+
+    fn custom_field_def(&self, name: &str) -> Option<&CustomFieldDef> {
+        self.scroll_buffer.get_config().custom_fields.iter().find(|def| def.name == name)
+    }
+
+    /// Stores the line buffer into `field` of the customer being added or
+    /// edited, custom fields are validated against their declared type first.
+    fn store_field(&mut self, field: &CustomerField) -> io::Result<bool> {
+        let mut value = self.line_buffer.get_string();
+        if let CustomerField::Custom(name) = field {
+            if let Some(def) = self.custom_field_def(name) {
+                match def.validate(&value) {
+                    Ok(valid) => value = valid,
+                    Err(e) => {
+                        self.status_line.set_message(e)?;
+                        return Ok(false);
+                    }
+                }
+            }
+        }
+        self.temp_customer.set_field(field, value);
+
+        Ok(true)
+    }
+
     pub fn enter(&mut self) -> io::Result<()> {
         log::info!("Enter pressed");
-        match self.mode {
+        match self.mode.clone() {
             EditorMode::Normal | EditorMode::Delete => {
                 self.set_mode(EditorMode::Normal)?;
                 self.filter()?;
             },
-            EditorMode::AddCompanyName => {
-                self.temp_customer.set_company_name(self.line_buffer.get_string());
-                self.set_mode(EditorMode::AddContactName)?;
+            EditorMode::Passphrase => self.unlock()?,
+            EditorMode::Command => {
+                // Runs before leaving the mode so the filter of the query is still in place
+                let result = self.run_command(&self.line_buffer.get_string());
+                if result.is_ok() && self.csv_import.is_some() {
+                    return self.set_mode(EditorMode::CsvMapping);
+                }
+                self.set_mode(EditorMode::Normal)?;
+                match result {
+                    Ok(message) => self.status_line.set_message(message)?,
+                    Err(e) => self.report(Err(e))?,
+                }
             },
-            EditorMode::AddContactName => {
-                self.temp_customer.set_contact_name(self.line_buffer.get_string());
-                self.set_mode(EditorMode::AddPhoneNumber)?;
+            EditorMode::Add(field) => {
+                if !self.store_field(&field)? {
+                    return Ok(());
+                }
+                match field.next(&self.scroll_buffer.get_config().custom_fields) {
+                    Some(next) => self.set_mode(EditorMode::Add(next))?,
+                    None => {
+                        let mut customer = self.temp_customer.clone();
+                        let result = self.place_in_company(&mut customer)
+                            .and_then(|_| self.apply_add(customer.clone()));
+                        self.set_mode(EditorMode::Normal)?;
+                        if let Some(company_id) = customer.company_id {
+                            self.scroll_buffer.expand(company_id)?;
+                        }
+                        self.filter()?;
+                        self.scroll_buffer.select_customer(customer.id)?;
+                        self.report(result)?;
+                    }
+                }
+            },
+            EditorMode::Edit(field) => {
+                if !self.store_field(&field)? {
+                    return Ok(());
+                }
+                match field.next(&self.scroll_buffer.get_config().custom_fields) {
+                    Some(next) => self.set_mode(EditorMode::Edit(next))?,
+                    None => {
+                        let mut customer = self.temp_customer.clone();
+                        let result = self.place_in_company(&mut customer)
+                            .and_then(|_| self.apply_update(customer.clone()))
+                            .and_then(|_| self.rename_people(&customer));
+                        self.set_mode(EditorMode::Normal)?;
+                        self.filter()?;
+                        self.report(result)?;
+                    }
+                }
+            },
+            EditorMode::SelectPhoneNumber => {
+                let count = self.scroll_buffer.get_selected_customer().map(|c| c.phones.len()).unwrap_or(0);
+                match self.line_buffer.get_string().trim().parse::<usize>() {
+                    Ok(choice) if choice >= 1 && choice <= count => {
+                        let result = self.scroll_buffer.dial_customer(Some(choice - 1));
+                        self.set_mode(EditorMode::Normal)?;
+                        self.dialled(result)?;
+                    },
+                    _ => {
+                        self.status_line.set_message(format!("Invalid choice, enter a number between 1 and {}", count))?;
+                    }
+                }
             },
-            EditorMode::AddPhoneNumber => {
-                self.temp_customer.set_phone_number(self.line_buffer.get_string());
-                self.scroll_buffer.add_customer(self.temp_customer.clone());
+            EditorMode::EditTags => {
+                let mut result = Ok(());
+                if let Some(customer) = self.scroll_buffer.get_selected_customer() {
+                    let mut customer = customer.clone();
+                    customer.set_tags(self.line_buffer.get_string());
+                    result = self.apply_update(customer);
+                }
                 self.set_mode(EditorMode::Normal)?;
-                self.filter()?;
+                self.report(result)?;
+            },
+            EditorMode::MergeConflicts => {
+                if let Some(index) = self.list_view.get_selected_index() {
+                    let conflict = self.conflicts.remove(index);
+                    let result = match conflict.alternative {
+                        Some(customer) => self.apply_update(customer),
+                        None => self.apply_delete(conflict.id, Utc::now()),
+                    };
+                    if self.conflicts.is_empty() {
+                        self.set_mode(EditorMode::Normal)?;
+                    } else {
+                        self.set_mode(EditorMode::MergeConflicts)?;
+                    }
+                    self.report(result)?;
+                }
+            },
+            EditorMode::CsvMapping => self.set_mode(EditorMode::CsvPreview)?,
+            EditorMode::Duplicates => {
+                let group = self.list_view.get_selected_index().and_then(|i| self.duplicate_groups.get(i));
+                if let Some(group) = group {
+                    let customers = group.iter().filter_map(|id| self.scroll_buffer.get_customer(*id)).cloned().collect();
+                    self.merge_plan = Some(MergePlan::new(customers, &self.scroll_buffer.get_config().custom_fields));
+                    self.set_mode(EditorMode::MergeDuplicates)?;
+                }
             },
-            EditorMode::EditCompanyName => {
-                self.temp_customer.set_company_name(self.line_buffer.get_string());
-                self.set_mode(EditorMode::EditContactName)?;
+            EditorMode::MergeDuplicates => self.merge_duplicates()?,
+            EditorMode::AddNoteKind => {
+                match NoteKind::parse(&self.line_buffer.get_string()) {
+                    Some(kind) => {
+                        self.temp_note_kind = kind;
+                        self.set_mode(EditorMode::AddNoteText)?;
+                    },
+                    None => {
+                        self.status_line.set_message("Invalid note kind, use call, meeting, email or note".to_string())?;
+                    }
+                }
+            },
+            EditorMode::AddTaskText => {
+                let text = self.line_buffer.get_string();
+                if text.trim().is_empty() {
+                    return self.set_mode(EditorMode::Normal);
+                }
+                self.temp_task.text = text.trim().to_string();
+                self.set_mode(EditorMode::AddTaskDue)?;
             },
-            EditorMode::EditContactName => {
-                self.temp_customer.set_contact_name(self.line_buffer.get_string());
-                self.set_mode(EditorMode::EditPhoneNumber)?;
+            EditorMode::AddTaskDue => {
+                match Task::parse_due(&self.line_buffer.get_string(), Local::now()) {
+                    Some(due) => {
+                        self.temp_task.due = due;
+                        self.set_mode(EditorMode::AddTaskPriority)?;
+                    },
+                    None => {
+                        self.status_line.set_message("Invalid due time, use YYYY-MM-DD HH:MM, today, tomorrow, +3d or friday".to_string())?;
+                    }
+                }
             },
-            EditorMode::EditPhoneNumber => {
-                self.temp_customer.set_phone_number(self.line_buffer.get_string());
-                self.scroll_buffer.update_customer(self.temp_customer.clone());
+            EditorMode::AddTaskPriority => {
+                match Priority::parse(&self.line_buffer.get_string()) {
+                    Some(priority) => {
+                        self.temp_task.priority = priority;
+                        let mut result = Ok(());
+                        if let Some(customer) = self.scroll_buffer.get_selected_customer() {
+                            let mut customer = customer.clone();
+                            customer.tasks.push(self.temp_task.clone());
+                            result = self.apply_update(customer);
+                        }
+                        self.set_mode(EditorMode::Normal)?;
+                        self.report(result)?;
+                    },
+                    None => {
+                        self.status_line.set_message("Invalid priority, use high, normal or low".to_string())?;
+                    }
+                }
+            },
+            EditorMode::RecentCalls => {
+                let selected = self.list_view.get_selected_index().and_then(|i| self.recent_calls.get(i)).copied();
+                if let Some(customer_id) = selected.filter(|id| self.scroll_buffer.get_customer(*id).is_some()) {
+                    self.set_mode(EditorMode::Normal)?;
+                    self.scroll_buffer.reveal(customer_id)?;
+                    self.scroll_buffer.select_customer(customer_id)?;
+                }
+            },
+            EditorMode::Agenda => {
+                let selected = self.list_view.get_selected_index().and_then(|i| self.agenda.get(i)).copied();
+                if let Some((customer_id, _)) = selected {
+                    self.set_mode(EditorMode::Normal)?;
+                    self.scroll_buffer.reveal(customer_id)?;
+                    self.scroll_buffer.select_customer(customer_id)?;
+                }
+            },
+            EditorMode::AddNoteText => {
+                let text = self.line_buffer.get_string();
+                let mut result = Ok(());
+                if !text.trim().is_empty() {
+                    if let Some(customer) = self.scroll_buffer.get_selected_customer() {
+                        let mut customer = customer.clone();
+                        customer.add_note(self.temp_note_kind, text.trim().to_string());
+                        result = self.apply_update(customer);
+                    }
+                }
                 self.set_mode(EditorMode::Normal)?;
-                self.filter()?;
+                self.report(result)?;
             },
             _ => {
                 // Ignore the enter key
@@ -213,19 +679,44 @@ impl Editor {
         self.scroll_buffer.load_config(self.config_path.clone()).expect("Error loading config");
         log::info!("Finished loading config...");
 
+        let mut locked = false;
         if self.sample_data {
             log::info!("Loading sample data...");
             self.scroll_buffer.load_sample_data();
         } else {
             log::info!("Loading customers...");
-            self.scroll_buffer.load_customers(self.file_path.clone());
+            let kind = StorageKind::resolve(self.backend, self.file_path.as_deref(), self.scroll_buffer.get_config().backend);
+            let file_path = self.file_path.clone().unwrap_or_else(|| kind.default_path());
+            let result = self.scroll_buffer.open_storage(kind, file_path.clone());
+            match self.scroll_buffer.lock_storage() {
+                Ok(true) => {},
+                Ok(false) => {
+                    self.lock_warning = Some(format!("{} is open in another instance, changes may conflict", file_path.display()));
+                },
+                Err(e) => log::error!("Error locking {}: {}", file_path.display(), e),
+            }
+            match result.and_then(|_| self.scroll_buffer.is_encrypted()) {
+                Ok(true) => {
+                    locked = true;
+                    // Until unlocked the buffer is empty, saving it would wipe the file
+                    self.load_error = Some("The contacts file is still locked, saving disabled".to_string());
+                },
+                Ok(false) => {
+                    let result = self.scroll_buffer.load_customers();
+                    self.loaded(result);
+                },
+                Err(e) => self.loaded(Err(e)),
+            }
         }
 
         self.filter()?;
+        self.refresh_overdue()?;
         self.line_buffer.draw()?;
         self.scroll_buffer.draw()?;
         self.line_buffer.sync_caret()?;
-        if self.no_splash {
+        if locked {
+            self.set_mode(EditorMode::Passphrase)?;
+        } else if self.no_splash {
             self.set_mode(EditorMode::Normal)?;
         } else {
             self.scroll_buffer.splash_screen()?;
@@ -237,9 +728,551 @@ impl Editor {
         Ok(())
     }
 
-    pub fn save(&mut self) -> io::Result<()> {
-        self.scroll_buffer.save_customers(self.file_path.clone())?;
-        self.status_line.set_message("Saved".to_string())?;
+    /// Runs a line typed at the command prompt, e.g. `import-vcard ~/new.vcf`,
+    /// returning the message to show.
+    fn run_command(&mut self, line: &str) -> io::Result<String> {
+        let line = line.trim();
+        let (command, argument) = match line.split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (line, ""),
+        };
+        if command.is_empty() {
+            return Ok("Normal Mode".to_string());
+        }
+        if argument.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} needs a file name", command)));
+        }
+
+        match command {
+            "import-vcard" => self.import_vcard(&expand_path(argument)),
+            "export-vcard" => self.export_vcard(argument),
+            "import-csv" => self.open_csv(argument),
+            "export-csv" => self.export_csv(argument),
+            "import-grandstream" => self.import_grandstream(&expand_path(argument)),
+            "export-grandstream" => self.export_grandstream(argument),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown command {}", command))),
+        }
+    }
+
+    fn import_vcard(&mut self, path: &Path) -> io::Result<String> {
+        let text = fs::read_to_string(path)?;
+        let (customers, summary) = vcard::import(&text, self.scroll_buffer.get_customers());
+        self.add_imported(customers)?;
+
+        Ok(format!("Imported {}: {}", path.display(), summary))
+    }
+
+    fn export_vcard(&mut self, argument: &str) -> io::Result<String> {
+        let (customers, path) = self.export_scope(argument);
+        if customers.is_empty() {
+            return Ok("Nothing to export".to_string());
+        }
+        fs::write(&path, vcard::export(customers.iter().copied()))?;
+
+        Ok(format!("Exported {} customers to {}", customers.len(), path.display()))
+    }
+
+    /// Writes the columns set by `csv_columns` in config.toml.
+    fn export_csv(&mut self, argument: &str) -> io::Result<String> {
+        let config = self.scroll_buffer.get_config();
+        let columns = csv_export::columns(&config.csv_columns, &config.custom_fields)?;
+        let (customers, path) = self.export_scope(argument);
+        if customers.is_empty() {
+            return Ok("Nothing to export".to_string());
+        }
+        fs::write(&path, csv_export::export(customers.iter().copied(), &columns)?)?;
+
+        Ok(format!("Exported {} customers to {}", customers.len(), path.display()))
+    }
+
+    fn import_grandstream(&mut self, path: &Path) -> io::Result<String> {
+        let text = fs::read_to_string(path)?;
+        let (customers, summary) = grandstream::import(&text, self.scroll_buffer.get_customers())?;
+        self.add_imported(customers)?;
+
+        Ok(format!("Imported {}: {}", path.display(), summary))
+    }
+
+    /// Writes the phonebook with the accounts set by `grandstream_accounts`
+    /// in config.toml.
+    fn export_grandstream(&mut self, argument: &str) -> io::Result<String> {
+        let config = self.scroll_buffer.get_config();
+        let accounts = AccountMap { accounts: &config.grandstream_accounts, default: config.line.account() };
+        let (customers, path) = self.export_scope(argument);
+        if customers.is_empty() {
+            return Ok("Nothing to export".to_string());
+        }
+        fs::write(&path, grandstream::export(customers.iter().copied(), &accounts))?;
+
+        Ok(format!("Exported {} customers to {}", customers.len(), path.display()))
+    }
+
+    /// The customers to export and the file to write. These are the
+    /// customers shown, or with `selected` or `all` before the file name the
+    /// selected customer or every customer.
+    fn export_scope(&self, argument: &str) -> (Vec<&Customer>, PathBuf) {
+        let (scope, path) = match argument.split_once(char::is_whitespace) {
+            Some((scope, path)) if scope == "selected" || scope == "all" => (scope, path.trim()),
+            _ => ("filtered", argument),
+        };
+        let customers: Vec<&Customer> = match scope {
+            "selected" => self.scroll_buffer.get_selected_customer().into_iter().collect(),
+            "all" => self.scroll_buffer.get_customers().iter().collect(),
+            _ => self.scroll_buffer.get_filtered_customers(),
+        };
+
+        (customers, expand_path(path))
+    }
+
+    /// Reads a CSV file for the mapping step, `-d` and `-e` before the file
+    /// name override the detected delimiter and encoding.
+    fn open_csv(&mut self, argument: &str) -> io::Result<String> {
+        let mut words = argument.split_whitespace().peekable();
+        let mut delimiter = None;
+        let mut encoding = None;
+        while let Some(option) = words.next_if(|w| *w == "-d" || *w == "-e") {
+            let value = words.next()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} needs a value", option)))?;
+            if option == "-d" {
+                delimiter = Some(csv_import::parse_delimiter(value)?);
+            } else {
+                encoding = Some(value.to_string());
+            }
+        }
+        let path = words.collect::<Vec<_>>().join(" ");
+        if path.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "import-csv needs a file name"));
+        }
+        let custom_fields = self.scroll_buffer.get_config().custom_fields.clone();
+        let import = CsvImport::open(expand_path(&path), delimiter, encoding.as_deref(), &custom_fields)?;
+        let message = format!("Read {}", import.path.display());
+        self.csv_import = Some(import);
+
+        Ok(message)
+    }
+
+    fn csv_mapping_items(&self) -> (String, Vec<String>) {
+        match &self.csv_import {
+            Some(import) => (format!("Columns of {}: {}", import.path.display(), import.describe()), import.mapping_rows()),
+            None => (String::new(), Vec::new()),
+        }
+    }
+
+    /// Handles the keys of the mapping step, the list keeps its position so
+    /// the column just changed stays selected.
+    fn csv_mapping_key(&mut self, c: char, forward: bool) -> io::Result<()> {
+        let custom_fields = self.scroll_buffer.get_config().custom_fields.clone();
+        let column = self.list_view.get_selected_index();
+        let result = match (self.csv_import.as_mut(), c) {
+            (Some(import), 'd') => import.next_delimiter(&custom_fields),
+            (Some(import), 'e') => import.next_encoding(&custom_fields),
+            (Some(import), _) => {
+                if let Some(column) = column {
+                    import.cycle_field(column, forward, &custom_fields);
+                }
+                Ok(())
+            },
+            (None, _) => Ok(()),
+        };
+        let (title, items) = self.csv_mapping_items();
+        self.list_view.update_items(title, items)?;
+        if let Err(e) = result {
+            self.status_line.set_message(format!("Error: {}", e))?;
+        }
+
+        Ok(())
+    }
+
+    /// Adds the customers of the previewed CSV file, reporting the rows that
+    /// failed.
+    fn import_csv(&mut self) -> io::Result<()> {
+        let import = match self.csv_import.take() {
+            Some(import) => import,
+            None => return Ok(()),
+        };
+        let (customers, errors) = import.to_customers(&self.scroll_buffer.get_config().custom_fields);
+        let count = customers.len();
+        let result = self.add_imported(customers);
+        self.set_mode(EditorMode::Normal)?;
+        self.report(result)?;
+        let mut message = format!("Imported {} customers from {}", count, import.path.display());
+        if !errors.is_empty() {
+            let lines: Vec<String> = errors.iter().map(|e| e.line.to_string()).collect();
+            message.push_str(&format!(", {} rows failed (lines {})", errors.len(), lines.join(", ")));
+        }
+        self.status_line.set_message(message)?;
+
+        Ok(())
+    }
+
+    /// Records the outcome of loading the customers and purges the trash.
+    fn loaded(&mut self, result: io::Result<()>) {
+        match result {
+            Ok(_) => {},
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                log::info!("No contacts file yet, starting with an empty list");
+            },
+            Err(e) => {
+                // Saving now would overwrite whatever we failed to read
+                log::error!("Error loading customers: {}", e);
+                self.load_error = Some(format!("Load failed, saving disabled: {}", e));
+                return;
+            }
+        }
+        match self.scroll_buffer.purge_expired_trash() {
+            Ok(0) => {},
+            Ok(count) => log::info!("Purged {} customers from the trash", count),
+            Err(e) => log::error!("Error purging the trash: {}", e),
+        }
+    }
+
+    /// Unlocks and loads the encrypted contacts file with the passphrase
+    /// typed in, asking again when it is wrong.
+    fn unlock(&mut self) -> io::Result<()> {
+        let passphrase = self.line_buffer.take_secret()?;
+        let result = self.scroll_buffer.unlock(&passphrase)
+            .and_then(|_| self.scroll_buffer.load_customers());
+        if let Err(e) = &result {
+            if e.kind() == io::ErrorKind::PermissionDenied {
+                log::info!("Unlocking failed: {}", e);
+                return self.status_line.set_message(format!("{}, try again", e));
+            }
+        }
+
+        self.load_error = None;
+        self.loaded(result);
+        self.refresh_overdue()?;
+        if self.no_splash || self.load_error.is_some() {
+            self.set_mode(EditorMode::Normal)
+        } else {
+            self.set_mode(EditorMode::SplashScreen)
+        }
+    }
+
+    /// Saves the customers, reporting the outcome on the status line.
+    /// Returns false if the save failed, so callers know not to quit.
+    pub fn save(&mut self) -> io::Result<bool> {
+        if self.sample_data {
+            self.status_line.set_message("Sample data is never saved".to_string())?;
+            return Ok(true);
+        }
+        if let Some(e) = &self.load_error {
+            self.status_line.set_message(e.clone())?;
+            return Ok(false);
+        }
+        match self.scroll_buffer.changed_externally() {
+            Ok(false) => {},
+            Ok(true) => {
+                self.set_mode(EditorMode::SaveConflict)?;
+                return Ok(false);
+            },
+            Err(e) => {
+                log::error!("Error checking the contacts file: {}", e);
+                self.status_line.set_message(format!("Save failed: {}", e))?;
+                return Ok(false);
+            }
+        }
+        self.write()
+    }
+
+    /// Saves through `save` once the configured idle time or number of edits
+    /// is reached. Only runs in normal mode so it never interrupts a prompt.
+    fn autosave(&mut self) -> io::Result<()> {
+        if self.sample_data || self.load_error.is_some() || self.mode != EditorMode::Normal
+            || !self.scroll_buffer.is_modified() {
+            return Ok(());
+        }
+        // Saving or reloading resets the count below the mark
+        let edits_since_save = self.scroll_buffer.edits_since_save();
+        self.autosave_mark = self.autosave_mark.min(edits_since_save);
+        if !self.scroll_buffer.get_config().autosave_due(self.last_activity.elapsed(), edits_since_save - self.autosave_mark) {
+            return Ok(());
+        }
+
+        log::info!("Autosaving");
+        let saved = self.save()?;
+        // A failed attempt waits for another idle period or batch of edits
+        self.last_activity = Instant::now();
+        self.autosave_mark = self.scroll_buffer.edits_since_save();
+        if saved {
+            self.status_line.set_autosaved_at(Local::now())?;
+        }
+
+        Ok(())
+    }
+
+    /// Flushes the customers to storage without checking for external changes.
+    fn write(&mut self) -> io::Result<bool> {
+        let result = self.scroll_buffer.save_customers();
+        self.refresh_modified()?;
+        match result {
+            Ok(_) => {
+                self.status_line.set_message("Saved".to_string())?;
+                Ok(true)
+            },
+            Err(e) => {
+                log::error!("Error saving customers: {}", e);
+                self.status_line.set_message(format!("Save failed: {}", e))?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Adds a customer through the scroll buffer and records it for undo.
+    fn apply_add(&mut self, customer: Customer) -> io::Result<()> {
+        let id = customer.id;
+        self.scroll_buffer.add_customer(customer)?;
+        if let Some(added) = self.scroll_buffer.get_customer(id) {
+            self.history.record(Change::Added(added.clone()));
+        }
+
+        Ok(())
+    }
+
+    /// Updates a customer through the scroll buffer and records it for undo.
+    fn apply_update(&mut self, customer: Customer) -> io::Result<()> {
+        let id = customer.id;
+        let before = self.scroll_buffer.update_customer(customer)?;
+        if let Some(after) = self.scroll_buffer.get_customer(id).cloned() {
+            self.history.record(match before {
+                Some(before) => Change::Updated { before: Box::new(before), after: Box::new(after) },
+                None => Change::Added(after),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Adds imported customers, people go under the company of the same
+    /// name. Companies are added first so people find them.
+    fn add_imported(&mut self, mut customers: Vec<Customer>) -> io::Result<()> {
+        customers.sort_by_key(|c| !c.is_company());
+        for mut customer in customers {
+            self.place_in_company(&mut customer)?;
+            self.apply_add(customer)?;
+        }
+
+        Ok(())
+    }
+
+    /// Links a person to the company named in `name`, adding the company
+    /// when there is none. Companies, and people without a company name,
+    /// stand on their own.
+    fn place_in_company(&mut self, customer: &mut Customer) -> io::Result<()> {
+        if customer.name.trim().is_empty() || customer.get_contact_name().trim().is_empty()
+            || !self.scroll_buffer.people_of(customer.id).is_empty() {
+            customer.company_id = None;
+            return Ok(());
+        }
+        // Keep the company the person was added to, another one may share its name
+        let current = customer.company_id
+            .and_then(|id| self.scroll_buffer.get_customer(id))
+            .filter(|c| c.name.trim().eq_ignore_ascii_case(customer.name.trim()))
+            .map(|c| (c.id, c.name.clone()));
+        let found = current.or_else(|| self.scroll_buffer.find_company(&customer.name, Some(customer.id))
+            .map(|c| (c.id, c.name.clone())));
+        let (id, name) = match found {
+            Some(found) => found,
+            None => {
+                let mut company = Customer::new();
+                company.name = customer.name.trim().to_string();
+                let found = (company.id, company.name.clone());
+                self.apply_add(company)?;
+                found
+            },
+        };
+        customer.company_id = Some(id);
+        customer.name = name;
+
+        Ok(())
+    }
+
+    /// Copies a company's name to its people after it is edited.
+    fn rename_people(&mut self, company: &Customer) -> io::Result<()> {
+        let renamed: Vec<Customer> = self.scroll_buffer.people_of(company.id).into_iter()
+            .filter(|p| p.name != company.name)
+            .cloned()
+            .collect();
+        for mut person in renamed {
+            person.name = company.name.clone();
+            self.apply_update(person)?;
+        }
+
+        Ok(())
+    }
+
+    /// Deletes a customer through the scroll buffer and records it for undo.
+    fn apply_delete(&mut self, id: Uuid, deleted_at: DateTime<Utc>) -> io::Result<()> {
+        if let Some(deleted) = self.scroll_buffer.delete_customer(id, deleted_at)? {
+            self.history.record(Change::Deleted(deleted));
+        }
+
+        Ok(())
+    }
+
+    pub fn undo(&mut self) -> io::Result<()> {
+        if self.mode != EditorMode::Normal {
+            return Ok(());
+        }
+        let change = match self.history.undo() {
+            Some(change) => change,
+            None => return self.status_line.set_message("Nothing to undo".to_string()),
+        };
+        let result = match &change {
+            Change::Added(customer) => self.scroll_buffer.purge(customer.id).map(|_| ()),
+            Change::Updated { before, .. } => self.scroll_buffer.restore_customer((**before).clone()).map(|_| ()),
+            Change::Deleted(customer) => self.scroll_buffer.restore_customer(customer.clone()).map(|_| ()),
+        };
+        self.filter()?;
+        self.refresh_overdue()?;
+        match result {
+            Ok(_) => self.status_line.set_message(format!("Undid {}", change.describe())),
+            Err(e) => {
+                // Put the change back on the undo stack, it never happened
+                self.history.redo();
+                self.status_line.set_message(format!("Undo failed: {}", e))
+            }
+        }
+    }
+
+    pub fn redo(&mut self) -> io::Result<()> {
+        if self.mode != EditorMode::Normal {
+            return Ok(());
+        }
+        let change = match self.history.redo() {
+            Some(change) => change,
+            None => return self.status_line.set_message("Nothing to redo".to_string()),
+        };
+        let result = match &change {
+            Change::Added(customer) => self.scroll_buffer.restore_customer(customer.clone()).map(|_| ()),
+            Change::Updated { after, .. } => self.scroll_buffer.restore_customer((**after).clone()).map(|_| ()),
+            Change::Deleted(customer) => self.scroll_buffer.delete_customer(customer.id, Utc::now()).map(|_| ()),
+        };
+        self.filter()?;
+        self.refresh_overdue()?;
+        match result {
+            Ok(_) => self.status_line.set_message(format!("Redid {}", change.describe())),
+            Err(e) => {
+                // Put the change back on the redo stack, it never happened
+                self.history.undo();
+                self.status_line.set_message(format!("Redo failed: {}", e))
+            }
+        }
+    }
+
+    /// Takes the customer selected in the trash view back out of the trash,
+    /// a company comes back with the people deleted along with it.
+    fn restore_from_trash(&mut self) -> io::Result<()> {
+        let id = match self.selected_trash_id() {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+        let mut ids = vec![id];
+        ids.extend(self.scroll_buffer.trashed_people_of(id));
+        let people = ids.len() - 1;
+        let result = ids.into_iter().try_for_each(|id| self.scroll_buffer.restore_from_trash(id).map(|before| {
+            if let (Some(before), Some(after)) = (before, self.scroll_buffer.get_customer(id)) {
+                self.history.record(Change::Updated { before: Box::new(before), after: Box::new(after.clone()) });
+            }
+        }));
+        self.set_mode(EditorMode::Trash)?;
+        self.refresh_modified()?;
+        self.refresh_overdue()?;
+        match result {
+            Ok(_) if people == 1 => self.status_line.set_message("Restored with its 1 person".to_string()),
+            Ok(_) if people > 1 => self.status_line.set_message(format!("Restored with its {} people", people)),
+            Ok(_) => self.status_line.set_message("Restored".to_string()),
+            Err(e) => self.report(Err(e)),
+        }
+    }
+
+    /// Asks before purging the customer selected in the trash view.
+    fn confirm_purge(&mut self) -> io::Result<()> {
+        let index = match self.list_view.get_selected_index() {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+        let (id, prompt) = match self.scroll_buffer.get_trash().get(index) {
+            Some(customer) => (customer.id, format!("Purge {} for good, it can not be undone (y/n): ", customer)),
+            None => return Ok(()),
+        };
+        self.purge_pending = Some(id);
+        self.line_buffer.set_prompt(prompt)?;
+        self.status_line.set_message("PurgeMode".to_string())
+    }
+
+    fn cancel_purge(&mut self) -> io::Result<()> {
+        self.purge_pending = None;
+        self.line_buffer.set_prompt("".to_string())?;
+        self.status_line.set_message("(r)estore, (p)urge, Esc to return".to_string())
+    }
+
+    /// Deletes a customer in the trash for good, it can not be undone.
+    fn purge_from_trash(&mut self, id: Uuid) -> io::Result<()> {
+        let result = self.scroll_buffer.purge(id);
+        if result.is_ok() {
+            self.history.forget(id);
+        }
+        self.set_mode(EditorMode::Trash)?;
+        self.refresh_modified()?;
+        self.refresh_overdue()?;
+        match result {
+            Ok(_) => self.status_line.set_message("Purged".to_string()),
+            Err(e) => self.report(Err(e)),
+        }
+    }
+
+    fn selected_trash_id(&self) -> Option<Uuid> {
+        let index = self.list_view.get_selected_index()?;
+        self.scroll_buffer.get_trash().get(index).map(|c| c.id)
+    }
+
+    /// Quits straight away when everything is saved, otherwise asks
+    /// whether to save or discard the changes first.
+    pub fn quit(&mut self) -> io::Result<()> {
+        if self.scroll_buffer.is_modified() {
+            self.set_mode(EditorMode::QuitConfirm)?;
+        } else {
+            self.quit = true;
+        }
+
+        Ok(())
+    }
+
+    fn resolve_quit(&mut self, c: char) -> io::Result<()> {
+        match c {
+            's' => {
+                self.set_mode(EditorMode::Normal)?;
+                // A conflict or failure leaves the save prompt or error showing
+                self.quit = self.save()?;
+            },
+            'd' => self.quit = true,
+            'c' | 'n' => self.set_mode(EditorMode::Normal)?,
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn refresh_modified(&mut self) -> io::Result<()> {
+        self.status_line.set_modified(self.scroll_buffer.is_modified())
+    }
+
+    /// Counts the overdue tasks again, after the tasks changed or were
+    /// loaded, and once a minute for those falling due.
+    fn refresh_overdue(&mut self) -> io::Result<()> {
+        self.overdue_refreshed = Instant::now();
+        self.status_line.set_overdue_count(self.scroll_buffer.overdue_count())
+    }
+
+    /// Shows a failed storage write on the status line instead of quitting.
+    fn report(&mut self, result: io::Result<()>) -> io::Result<()> {
+        self.refresh_modified()?;
+        self.refresh_overdue()?;
+        if let Err(e) = result {
+            log::error!("Error writing customer: {}", e);
+            self.status_line.set_message(format!("Error: {}", e))?;
+        }
 
         Ok(())
     }
@@ -247,34 +1280,100 @@ impl Editor {
     pub fn filter(&mut self) -> io::Result<()> {
         self.scroll_buffer.set_filter(self.line_buffer.get_string())?;
         self.status_line.set_results_count(self.scroll_buffer.get_results_count())?;
+        self.status_line.set_tag_counts(self.scroll_buffer.get_tag_counts())?;
+        self.refresh_modified()?;
 
         Ok(())
     }
 
-    pub fn call_customer(&self) -> io::Result<()> {
+    pub fn call_customer(&mut self) -> io::Result<()> {
         log::info!("Calling customer");
-        self.scroll_buffer.dial_customer()?;
+        let phone_count = self.scroll_buffer.get_selected_customer().map(|c| c.phones.len()).unwrap_or(0);
+        if phone_count > 1 {
+            self.set_mode(EditorMode::SelectPhoneNumber)?;
+        } else {
+            let result = self.scroll_buffer.dial_customer(None);
+            self.dialled(result)?;
+        }
 
         Ok(())
     }
 
+    /// Shows how the call went, or why it could not be logged.
+    fn dialled(&mut self, result: io::Result<Option<CallRecord>>) -> io::Result<()> {
+        match result {
+            Ok(Some(call)) => match call.error {
+                Some(e) => self.status_line.set_message(format!("Dialling {} failed: {}", call.number, e)),
+                None => self.status_line.set_message(format!("Dialled {}", call.number)),
+            },
+            Ok(None) => self.status_line.set_message("No phone number to dial".to_string()),
+            Err(e) => self.status_line.set_message(format!("Could not log the call: {}", e)),
+        }
+    }
+
     pub fn add_key(&mut self, c: char) -> io::Result<()> {
-        log::info!("Key pressed: {}", c);
-        if self.mode == EditorMode::SplashScreen {
-            
-            // Ignore any key presses that aren't space bar
+        if self.mode != EditorMode::Passphrase {
+            log::info!("Key pressed: {}", c);
+        }
+        if self.mode == EditorMode::Trash {
+            match (c, self.purge_pending) {
+                ('y', Some(id)) => self.purge_from_trash(id)?,
+                ('n', Some(_)) => self.cancel_purge()?,
+                (_, Some(_)) => {},
+                ('r', None) => self.restore_from_trash()?,
+                ('p', None) => self.confirm_purge()?,
+                _ => {}
+            }
+            return Ok(());
+        }
+        if self.mode == EditorMode::Agenda {
+            if c == 'd' {
+                self.complete_task()?;
+            }
+            return Ok(());
+        }
+        if self.mode == EditorMode::CsvMapping {
+            if c == 'd' || c == 'e' {
+                self.csv_mapping_key(c, true)?;
+            }
+            return Ok(());
+        }
+        if self.mode == EditorMode::CsvPreview {
+            match c {
+                'y' => self.import_csv()?,
+                'n' => self.set_mode(EditorMode::CsvMapping)?,
+                _ => {}
+            }
+            return Ok(());
+        }
+        if self.mode == EditorMode::SplashScreen || self.is_list_mode() {
+            // Ignore any key presses that aren't space bar, read-only
+            // views have nothing to type into
             return Ok(())
         }
+        if self.mode == EditorMode::SaveConflict {
+            self.resolve_save_conflict(c)?;
+            return Ok(());
+        }
+        if self.mode == EditorMode::QuitConfirm {
+            self.resolve_quit(c)?;
+            return Ok(());
+        }
         // Logic for handling delete mode
         if self.mode == EditorMode::Delete {
             log::info!("Delete mode");
             if c == 'y' {
                 log::info!("Deleting customer");
-                if self.scroll_buffer.get_selected_customer().is_some() {
+                if let Some(id) = self.scroll_buffer.get_selected_id() {
                     log::info!("Found we have a valid selected customer");
-                    self.scroll_buffer.delete_customer()?;
+                    // A company goes to the trash with its people
+                    let mut ids: Vec<Uuid> = self.scroll_buffer.people_of(id).iter().map(|p| p.id).collect();
+                    ids.push(id);
+                    let deleted_at = Utc::now();
+                    let result = ids.into_iter().try_for_each(|id| self.apply_delete(id, deleted_at));
                     self.set_mode(EditorMode::Normal)?;
                     self.filter()?;
+                    self.report(result)?;
                 }
             } else if c == 'n' {
                 log::info!("Not deleting customer");
@@ -297,13 +1396,220 @@ impl Editor {
         Ok(())
     }
 
+    fn resolve_save_conflict(&mut self, c: char) -> io::Result<()> {
+        match c {
+            'r' => {
+                let result = self.scroll_buffer.load_customers();
+                self.set_mode(EditorMode::Normal)?;
+                self.refresh_overdue()?;
+                match result {
+                    Ok(_) => self.status_line.set_message("Reloaded from disk, local changes discarded".to_string())?,
+                    Err(e) => self.status_line.set_message(format!("Reload failed: {}", e))?,
+                }
+            },
+            'o' => {
+                self.set_mode(EditorMode::Normal)?;
+                self.write()?;
+            },
+            'm' => {
+                match self.scroll_buffer.merge_external() {
+                    Ok(conflicts) => {
+                        self.conflicts = conflicts;
+                        self.filter()?;
+                        self.refresh_overdue()?;
+                        if !self.write()? {
+                            return Ok(());
+                        }
+                        if self.conflicts.is_empty() {
+                            self.set_mode(EditorMode::Normal)?;
+                            self.status_line.set_message("Merged and saved".to_string())?;
+                        } else {
+                            self.set_mode(EditorMode::MergeConflicts)?;
+                        }
+                    },
+                    Err(e) => {
+                        self.set_mode(EditorMode::Normal)?;
+                        self.status_line.set_message(format!("Merge failed: {}", e))?;
+                    }
+                }
+            },
+            'c' | 'n' => {
+                self.set_mode(EditorMode::Normal)?;
+                self.status_line.set_message("Not saved".to_string())?;
+            },
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn merge_items(&self) -> (String, Vec<String>) {
+        match &self.merge_plan {
+            Some(plan) => (format!("Merge {} customers into {}", plan.customers.len(), plan.customers[0]), plan.rows()),
+            None => (String::new(), Vec::new()),
+        }
+    }
+
+    /// Updates the surviving customer with the values picked and moves the
+    /// others to the trash, then goes back to the remaining duplicates.
+    fn merge_duplicates(&mut self) -> io::Result<()> {
+        let plan = match self.merge_plan.take() {
+            Some(plan) => plan,
+            None => return Ok(()),
+        };
+        let survivor = plan.merged();
+        let mut result = self.apply_update(survivor.clone());
+        for other in plan.absorbed() {
+            if result.is_err() {
+                break;
+            }
+            // People of an absorbed company move to the survivor
+            let people: Vec<Customer> = self.scroll_buffer.people_of(other.id).into_iter().cloned().collect();
+            for mut person in people {
+                person.company_id = Some(survivor.id);
+                person.name = survivor.name.clone();
+                result = result.and_then(|_| self.apply_update(person));
+            }
+            result = result.and_then(|_| self.apply_delete(other.id, Utc::now()));
+        }
+        let failed = result.is_err();
+        if self.scroll_buffer.find_duplicates().is_empty() {
+            self.set_mode(EditorMode::Normal)?;
+        } else {
+            self.set_mode(EditorMode::Duplicates)?;
+        }
+        self.report(result)?;
+        if !failed {
+            self.status_line.set_message(format!("Merged {} customers into {}, the others are in the trash",
+                                                 plan.customers.len(), survivor))?;
+        }
+
+        Ok(())
+    }
+
+    /// Open tasks grouped as overdue, due today and upcoming.
+    fn agenda_items(&mut self) -> (String, Vec<String>) {
+        let now = Local::now();
+        let agenda = self.scroll_buffer.agenda();
+        let (mut overdue, mut today) = (0, 0);
+        let items = agenda.iter()
+            .map(|(customer, task)| {
+                let section = if task.due < now {
+                    overdue += 1;
+                    "Overdue"
+                } else if task.due.with_timezone(&Local).date_naive() == now.date_naive() {
+                    today += 1;
+                    "Today"
+                } else {
+                    "Upcoming"
+                };
+                format!("{:<9}{}  - {}", section, task, customer)
+            })
+            .collect();
+        let title = format!("Agenda: {} overdue, {} today, {} upcoming", overdue, today, agenda.len() - overdue - today);
+        self.agenda = agenda.iter().map(|(customer, task)| (customer.id, task.id)).collect();
+
+        (title, items)
+    }
+
+    /// Marks the selected agenda task done, it drops off the agenda.
+    fn complete_task(&mut self) -> io::Result<()> {
+        let selected = self.list_view.get_selected_index().and_then(|i| self.agenda.get(i)).copied();
+        let (customer_id, task_id) = match selected {
+            Some(selected) => selected,
+            None => return Ok(()),
+        };
+        let mut result = Ok(());
+        if let Some(customer) = self.scroll_buffer.get_customer(customer_id) {
+            let mut customer = customer.clone();
+            if let Some(task) = customer.tasks.iter_mut().find(|t| t.id == task_id) {
+                task.done = true;
+            }
+            result = self.apply_update(customer);
+        }
+        let (title, items) = self.agenda_items();
+        self.list_view.update_items(title, items)?;
+        self.report(result)
+    }
+
+    fn cycle_merge_value(&mut self, forward: bool) -> io::Result<()> {
+        if let (Some(plan), Some(row)) = (self.merge_plan.as_mut(), self.list_view.get_selected_index()) {
+            plan.cycle(row, forward);
+        }
+        let (title, items) = self.merge_items();
+        self.list_view.update_items(title, items)
+    }
+
+    /// Modes that show the list view instead of the customers.
+    fn is_list_mode(&self) -> bool {
+        matches!(self.mode, EditorMode::History | EditorMode::MergeConflicts | EditorMode::Trash
+            | EditorMode::CsvMapping | EditorMode::CsvPreview | EditorMode::Duplicates | EditorMode::MergeDuplicates
+            | EditorMode::Agenda | EditorMode::RecentCalls)
+    }
+
     pub fn add_customer(&mut self) -> io::Result<()> {
-        self.set_mode(EditorMode::AddCompanyName)?;
+        self.temp_customer = Customer::new();
+        self.set_mode(EditorMode::Add(CustomerField::first()))?;
+        Ok(())
+    }
+
+    /// Adds a contact to the selected company, or to the company of the
+    /// selected person.
+    pub fn add_contact(&mut self) -> io::Result<()> {
+        let company = self.scroll_buffer.get_selected_customer()
+            .and_then(|c| match c.company_id {
+                Some(id) => self.scroll_buffer.get_customer(id),
+                None if c.is_company() && !c.name.trim().is_empty() => Some(c),
+                None => None,
+            })
+            .map(|c| (c.id, c.name.clone()));
+        let (id, name) = match company {
+            Some(company) => company,
+            None => return self.status_line.set_message("Select a company to add a contact to".to_string()),
+        };
+        self.temp_customer = Customer::new();
+        self.temp_customer.company_id = Some(id);
+        self.temp_customer.name = name;
+        self.set_mode(EditorMode::Add(CustomerField::ContactName))?;
+        Ok(())
+    }
+
+    /// Pins the selected customer as a favourite, or unpins them.
+    pub fn toggle_pinned(&mut self) -> io::Result<()> {
+        if self.mode != EditorMode::Normal {
+            return Ok(());
+        }
+        let mut customer = match self.scroll_buffer.get_selected_customer() {
+            Some(customer) => customer.clone(),
+            None => return self.status_line.set_message("No customer selected".to_string()),
+        };
+        customer.pinned = !customer.pinned;
+        let message = format!("{} {}", if customer.pinned { "Pinned" } else { "Unpinned" }, customer);
+        let result = self.apply_update(customer);
+        self.filter()?;
+        self.status_line.set_message(message)?;
+        self.report(result)
+    }
+
+    pub fn toggle_favourites_only(&mut self) -> io::Result<()> {
+        if self.mode != EditorMode::Normal {
+            return Ok(());
+        }
+        let favourites_only = self.scroll_buffer.toggle_favourites_only()?;
+        self.filter()?;
+        self.status_line.set_favourites_only(favourites_only)
+    }
+
+    pub fn toggle_expanded(&mut self) -> io::Result<()> {
+        if self.mode == EditorMode::Normal {
+            self.scroll_buffer.toggle_expanded()?;
+        }
         Ok(())
     }
 
     pub fn edit_customer(&mut self) -> io::Result<()> {
-        self.set_mode(EditorMode::EditCompanyName)?;
+        self.temp_customer = self.scroll_buffer.get_selected_customer().cloned().unwrap_or_else(Customer::new);
+        self.set_mode(EditorMode::Edit(CustomerField::first()))?;
         Ok(())
     }
 
@@ -312,6 +1618,48 @@ impl Editor {
         Ok(())
     }
 
+    pub fn edit_tags(&mut self) -> io::Result<()> {
+        if self.scroll_buffer.get_selected_customer().is_none() {
+            self.status_line.set_message("No customer selected".to_string())?;
+            return Ok(());
+        }
+        self.set_mode(EditorMode::EditTags)?;
+        Ok(())
+    }
+
+    pub fn add_task(&mut self) -> io::Result<()> {
+        if self.scroll_buffer.get_selected_customer().is_none() {
+            self.status_line.set_message("No customer selected".to_string())?;
+            return Ok(());
+        }
+        self.temp_task = Task::new(String::new(), Utc::now(), Priority::Normal);
+        self.set_mode(EditorMode::AddTaskText)?;
+        Ok(())
+    }
+
+    pub fn add_note(&mut self) -> io::Result<()> {
+        if self.scroll_buffer.get_selected_customer().is_none() {
+            self.status_line.set_message("No customer selected".to_string())?;
+            return Ok(());
+        }
+        self.set_mode(EditorMode::AddNoteKind)?;
+        Ok(())
+    }
+
+    pub fn show_history(&mut self) -> io::Result<()> {
+        if self.scroll_buffer.get_selected_customer().is_none() {
+            self.status_line.set_message("No customer selected".to_string())?;
+            return Ok(());
+        }
+        self.set_mode(EditorMode::History)?;
+        Ok(())
+    }
+
+    pub fn show_trash(&mut self) -> io::Result<()> {
+        self.set_mode(EditorMode::Trash)?;
+        Ok(())
+    }
+
     pub fn toggle_insert(&mut self) -> io::Result<()> {
         self.line_buffer.toggle_insert()?;
 
@@ -319,12 +1667,24 @@ impl Editor {
     }
 
     pub fn move_left(&mut self) -> io::Result<()> {
+        if self.mode == EditorMode::CsvMapping {
+            return self.csv_mapping_key(' ', false);
+        }
+        if self.mode == EditorMode::MergeDuplicates {
+            return self.cycle_merge_value(false);
+        }
         self.line_buffer.move_left()?;
 
         Ok(())
     }
 
     pub fn move_right(&mut self) -> io::Result<()> {
+        if self.mode == EditorMode::CsvMapping {
+            return self.csv_mapping_key(' ', true);
+        }
+        if self.mode == EditorMode::MergeDuplicates {
+            return self.cycle_merge_value(true);
+        }
         self.line_buffer.move_right()?;
 
         Ok(())
@@ -364,14 +1724,22 @@ impl Editor {
     }
 
     pub fn move_up(&mut self) -> io::Result<()> {
-        self.scroll_buffer.move_up()?;
+        if self.is_list_mode() {
+            self.list_view.move_up()?;
+        } else {
+            self.scroll_buffer.move_up()?;
+        }
         self.line_buffer.sync_caret()?;
 
         Ok(())
     }
 
     pub fn move_down(&mut self) -> io::Result<()> {
-        self.scroll_buffer.move_down()?;
+        if self.is_list_mode() {
+            self.list_view.move_down()?;
+        } else {
+            self.scroll_buffer.move_down()?;
+        }
         self.line_buffer.sync_caret()?;
 
         Ok(())
@@ -0,0 +1,242 @@
+use crate::customer::{Customer, PhoneLabel, PhoneNumber};
+use crate::vcard::{self, ImportSummary};
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind};
+
+/// Which handset account dials each number, by phone label, e.g.
+/// `mobile = 2`. Accounts are numbered from 1 as on the handset, numbers
+/// with other labels use `default`.
+#[derive(Debug, Clone)]
+pub struct AccountMap<'a> {
+    pub accounts: &'a BTreeMap<String, u8>,
+    pub default: u8,
+}
+
+impl AccountMap<'_> {
+    fn account(&self, label: &PhoneLabel) -> u8 {
+        let label = label.to_string().to_lowercase();
+        self.accounts.iter()
+            .find(|(name, _)| name.to_lowercase() == label)
+            .map(|(_, account)| *account)
+            .unwrap_or(self.default)
+            .max(1)
+    }
+}
+
+/// Writes the customers as a Grandstream `AddressBook` phonebook, the XML
+/// the handsets download from their phonebook server.
+pub fn export<'a>(customers: impl IntoIterator<Item = &'a Customer>, accounts: &AccountMap) -> String {
+    let mut lines = vec!["<?xml version=\"1.0\" encoding=\"UTF-8\"?>".to_string(), "<AddressBook>".to_string()];
+    for customer in customers {
+        let (first, last) = split_name(customer);
+        lines.push("  <Contact>".to_string());
+        lines.push(format!("    <FirstName>{}</FirstName>", escape(first)));
+        lines.push(format!("    <LastName>{}</LastName>", escape(last)));
+        if !customer.name.is_empty() {
+            lines.push(format!("    <Company>{}</Company>", escape(&customer.name)));
+        }
+        // Primary number first, the handset dials the first one by default
+        let phones = customer.phones.iter().filter(|p| p.primary).chain(customer.phones.iter().filter(|p| !p.primary));
+        for phone in phones {
+            let number = dial_string(&phone.number);
+            if number.is_empty() {
+                continue;
+            }
+            lines.push(format!("    <Phone type=\"{}\">", phone_type(&phone.label)));
+            lines.push(format!("      <phonenumber>{}</phonenumber>", escape(&number)));
+            // The XML counts accounts from 0
+            lines.push(format!("      <accountindex>{}</accountindex>", accounts.account(&phone.label) - 1));
+            lines.push("    </Phone>".to_string());
+        }
+        for email in customer.emails.iter() {
+            lines.push(format!("    <Mail type=\"Work\">{}</Mail>", escape(email)));
+        }
+        lines.push("  </Contact>".to_string());
+    }
+    lines.push("</AddressBook>".to_string());
+
+    let mut text = lines.join("\n");
+    text.push('\n');
+    text
+}
+
+/// Reads the contacts of a Grandstream phonebook and returns the new
+/// customers, leaving out those already in `existing`.
+pub fn import(text: &str, existing: &[Customer]) -> Result<(Vec<Customer>, ImportSummary), Error> {
+    let document = roxmltree::Document::parse(text)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("not a phonebook: {}", e)))?;
+    if !document.root_element().has_tag_name("AddressBook") {
+        return Err(Error::new(ErrorKind::InvalidData, "not a phonebook, there is no AddressBook"));
+    }
+    let contacts = document.root_element().children().filter(|node| node.has_tag_name("Contact"));
+
+    Ok(vcard::keep_new(contacts.map(to_customer), existing))
+}
+
+fn to_customer(contact: roxmltree::Node) -> Option<Customer> {
+    let mut customer = Customer::new();
+    let text = |name: &str| contact.children()
+        .find(|node| node.has_tag_name(name))
+        .and_then(|node| node.text())
+        .map(|text| text.trim().to_string())
+        .filter(|text| !text.is_empty());
+
+    let full_name = format!("{} {}", text("FirstName").unwrap_or_default(), text("LastName").unwrap_or_default())
+        .trim().to_string();
+    match text("Company") {
+        Some(company) => {
+            customer.set_company_name(company);
+            customer.contact_name = Some(full_name).filter(|n| !n.is_empty() && *n != customer.name);
+        },
+        // A person without a company is listed under their own name
+        None => customer.set_company_name(full_name),
+    }
+
+    for phone in contact.children().filter(|node| node.has_tag_name("Phone")) {
+        let number = phone.children()
+            .find(|node| node.has_tag_name("phonenumber"))
+            .and_then(|node| node.text())
+            .map(|text| text.trim())
+            .unwrap_or("");
+        if !number.is_empty() {
+            customer.phones.push(PhoneNumber::new(phone_label(phone.attribute("type")), number.to_string()));
+        }
+    }
+    customer.ensure_primary();
+
+    customer.emails = contact.children()
+        .filter(|node| node.has_tag_name("Mail"))
+        .filter_map(|node| node.text())
+        .map(|email| email.trim().to_string())
+        .filter(|email| !email.is_empty())
+        .collect();
+
+    if customer.name.is_empty() && customer.phones.is_empty() && customer.emails.is_empty() {
+        return None;
+    }
+
+    Some(customer)
+}
+
+/// The handset shows FirstName before LastName, so the contact's first word
+/// goes first and the rest last. A customer without a contact is shown by
+/// the company name alone.
+fn split_name(customer: &Customer) -> (&str, &str) {
+    match customer.contact_name.as_deref().map(|n| n.trim()).filter(|n| !n.is_empty()) {
+        Some(contact) => contact.split_once(' ').map(|(first, last)| (first, last.trim())).unwrap_or((contact, "")),
+        None => (customer.name.trim(), ""),
+    }
+}
+
+fn phone_type(label: &PhoneLabel) -> &'static str {
+    match label {
+        PhoneLabel::Office => "Work",
+        PhoneLabel::Mobile => "Cell",
+        PhoneLabel::Fax => "Fax",
+        PhoneLabel::Custom(label) if label.eq_ignore_ascii_case("home") => "Home",
+        PhoneLabel::Custom(_) => "Work",
+    }
+}
+
+fn phone_label(phone_type: Option<&str>) -> PhoneLabel {
+    match phone_type.map(|t| t.trim().to_lowercase()).as_deref() {
+        Some("cell") | Some("mobile") => PhoneLabel::Mobile,
+        Some("fax") => PhoneLabel::Fax,
+        Some("home") => PhoneLabel::Custom("home".to_string()),
+        _ => PhoneLabel::Office,
+    }
+}
+
+/// The handset dials the number as written, so spacing and punctuation
+/// are dropped.
+fn dial_string(number: &str) -> String {
+    number.chars().filter(|c| c.is_ascii_digit() || matches!(c, '+' | '*' | '#')).collect()
+}
+
+fn escape(value: &str) -> String {
+    let mut text = String::new();
+    for c in value.chars() {
+        match c {
+            '&' => text.push_str("&amp;"),
+            '<' => text.push_str("&lt;"),
+            '>' => text.push_str("&gt;"),
+            '"' => text.push_str("&quot;"),
+            '\'' => text.push_str("&apos;"),
+            c => text.push(c),
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn customer(company: &str, contact: Option<&str>) -> Customer {
+        let mut customer = Customer::new();
+        customer.set_company_name(company.to_string());
+        customer.contact_name = contact.map(|c| c.to_string());
+        customer
+    }
+
+    #[test]
+    fn names_split_into_first_and_last() {
+        assert_eq!(split_name(&customer("Acme", Some("Ann Marie  Lee"))), ("Ann", "Marie  Lee"));
+        assert_eq!(split_name(&customer("Acme", Some(" Cher "))), ("Cher", ""));
+        assert_eq!(split_name(&customer(" Acme ", Some(""))), ("Acme", ""));
+        assert_eq!(split_name(&customer("Acme", None)), ("Acme", ""));
+    }
+
+    #[test]
+    fn accounts_follow_the_labels() {
+        let accounts = BTreeMap::from([("Mobile".to_string(), 2), ("fax".to_string(), 0)]);
+        let map = AccountMap { accounts: &accounts, default: 3 };
+        assert_eq!(map.account(&PhoneLabel::Mobile), 2);
+        assert_eq!(map.account(&PhoneLabel::Office), 3);
+        // Accounts count from 1, a 0 would underflow the XML's index
+        assert_eq!(map.account(&PhoneLabel::Fax), 1);
+        assert_eq!(AccountMap { accounts: &BTreeMap::new(), default: 0 }.account(&PhoneLabel::Office), 1);
+
+        let mut acme = customer("Acme", None);
+        acme.phones = vec![
+            PhoneNumber::new(PhoneLabel::Office, "07 3333 4444".to_string()),
+            PhoneNumber { label: PhoneLabel::Mobile, number: "0412 345 678".to_string(), primary: true },
+            PhoneNumber::new(PhoneLabel::Fax, "07 3333 4445".to_string()),
+        ];
+        let text = export([&acme], &map);
+        assert!(text.contains("<Phone type=\"Cell\">\n      <phonenumber>0412345678</phonenumber>\n      <accountindex>1</accountindex>"));
+        assert!(text.contains("<phonenumber>0733334444</phonenumber>\n      <accountindex>2</accountindex>"));
+        assert!(text.contains("<phonenumber>0733334445</phonenumber>\n      <accountindex>0</accountindex>"));
+        // The primary number is listed first
+        assert!(text.find("0412345678").unwrap() < text.find("0733334444").unwrap());
+    }
+
+    #[test]
+    fn text_is_escaped_and_reads_back() {
+        let mut customer = customer("Smith & <Jones>", Some("Ann \"Nan\" O'Hara"));
+        customer.phones = vec![PhoneNumber::new(PhoneLabel::Office, "07 3333 4444".to_string())];
+        customer.ensure_primary();
+        let text = export([&customer], &AccountMap { accounts: &BTreeMap::new(), default: 1 });
+        assert!(text.contains("<Company>Smith &amp; &lt;Jones&gt;</Company>"));
+        assert!(text.contains("<LastName>&quot;Nan&quot; O&apos;Hara</LastName>"));
+
+        let (imported, summary) = import(&text, &[]).unwrap();
+        assert_eq!(summary.added, 1);
+        assert_eq!(imported[0].name, customer.name);
+        assert_eq!(imported[0].contact_name, customer.contact_name);
+    }
+
+    #[test]
+    fn contact_without_company_is_listed_by_name() {
+        let text = "<AddressBook>\n  <Contact>\n    <FirstName>Ann</FirstName>\n    <LastName>Lee</LastName>\n\
+                    <Phone type=\"Cell\"><phonenumber>0412345678</phonenumber></Phone>\n  </Contact>\n\
+                    <Contact><FirstName> </FirstName></Contact>\n</AddressBook>";
+        let (imported, summary) = import(text, &[]).unwrap();
+        assert_eq!((summary.added, summary.skipped), (1, 1));
+        assert_eq!(imported[0].name, "Ann Lee");
+        assert_eq!(imported[0].contact_name, None);
+        assert_eq!(imported[0].phones, [PhoneNumber { label: PhoneLabel::Mobile, number: "0412345678".to_string(), primary: true }]);
+
+        assert!(import("<Phonebook/>", &[]).is_err());
+    }
+}
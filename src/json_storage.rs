@@ -0,0 +1,315 @@
+use crate::contacts_file::ContactsFile;
+use crate::crypto::FileKey;
+use crate::customer::Customer;
+use crate::merge::{self, MergeResult};
+use crate::storage::Storage;
+use fs2::FileExt;
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+// Coarser than any file system we expect to save to, FAT rounds to 2s
+const MTIME_RESOLUTION: Duration = Duration::from_secs(2);
+
+/// What the contacts file looked like when we last read or wrote it.
+#[derive(Debug, PartialEq)]
+struct FileStamp {
+    modified: SystemTime,
+    len: u64,
+    hash: u64,
+    // When we read it, a write in the same mtime tick would not show
+    read_at: SystemTime,
+}
+
+impl FileStamp {
+    fn read(file_path: &Path) -> io::Result<Option<FileStamp>> {
+        let contents = match fs::read(file_path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let metadata = fs::metadata(file_path)?;
+
+        Ok(Some(FileStamp {
+            modified: metadata.modified()?,
+            len: metadata.len(),
+            hash: hash_contents(&contents),
+            read_at: SystemTime::now(),
+        }))
+    }
+
+    /// Whether the file's contents differ from ours. The hash decides, the
+    /// mtime only lets us skip reading the file when it can be trusted.
+    fn differs(&self, file_path: &Path) -> io::Result<bool> {
+        let metadata = match fs::metadata(file_path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+            Err(e) => return Err(e),
+        };
+        let settled = self.read_at.duration_since(self.modified).is_ok_and(|age| age > MTIME_RESOLUTION);
+        if settled && metadata.modified()? == self.modified && metadata.len() == self.len {
+            return Ok(false);
+        }
+        match fs::read(file_path) {
+            Ok(contents) => Ok(hash_contents(&contents) != self.hash),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(true),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn hash_contents(contents: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    contents.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Keeps the customers in memory and rewrites the whole contacts file on
+/// `flush`.
+pub struct JsonStorage {
+    file_path: PathBuf,
+    backups: usize,
+    customers: Vec<Customer>,
+    // The file as of the last load or save, the base of a three way merge
+    base: Vec<Customer>,
+    stamp: Option<FileStamp>,
+    lock: Option<File>,
+    dirty: bool,
+    // Set once unlocked, the file is then re-encrypted on every save
+    key: Option<FileKey>,
+}
+
+impl JsonStorage {
+    pub fn new(file_path: PathBuf, backups: usize) -> JsonStorage {
+        JsonStorage {
+            file_path,
+            backups,
+            customers: Vec::new(),
+            base: Vec::new(),
+            stamp: None,
+            lock: None,
+            dirty: false,
+            key: None,
+        }
+    }
+
+    /// Sets the key the next `flush` encrypts with, `None` saves plain text.
+    pub fn set_key(&mut self, key: Option<FileKey>) {
+        self.key = key;
+    }
+
+    fn lock_path(&self) -> PathBuf {
+        let file_name = self.file_path.file_name().and_then(|n| n.to_str()).unwrap_or("contacts.json");
+        self.file_path.with_file_name(format!("{}.lock", file_name))
+    }
+
+    fn load_theirs(&self) -> io::Result<Vec<Customer>> {
+        match Customer::load_customers(self.file_path.clone(), self.key.as_ref()) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            result => result,
+        }
+    }
+}
+
+impl Storage for JsonStorage {
+    fn load(&mut self) -> io::Result<Vec<Customer>> {
+        self.stamp = FileStamp::read(&self.file_path)?;
+        self.customers = Customer::load_customers(self.file_path.clone(), self.key.as_ref())?;
+        self.base = self.customers.clone();
+        self.dirty = false;
+
+        Ok(self.customers.clone())
+    }
+
+    fn insert(&mut self, customer: &Customer) -> io::Result<()> {
+        self.customers.push(customer.clone());
+        self.dirty = true;
+
+        Ok(())
+    }
+
+    fn update(&mut self, customer: &Customer) -> io::Result<()> {
+        match self.customers.iter_mut().find(|c| c.id == customer.id) {
+            Some(existing) => *existing = customer.clone(),
+            None => self.customers.push(customer.clone()),
+        }
+        self.dirty = true;
+
+        Ok(())
+    }
+
+    fn delete(&mut self, id: Uuid) -> io::Result<()> {
+        self.customers.retain(|c| c.id != id);
+        self.dirty = true;
+
+        Ok(())
+    }
+
+    fn query(&mut self, query: &str) -> io::Result<Vec<Customer>> {
+        let query = query.to_lowercase();
+
+        Ok(self.customers.iter()
+            .filter(|c| c.deleted_at.is_none() && c.matches(&query))
+            .cloned()
+            .collect())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Customer::save_customers(&self.customers, self.file_path.clone(), self.backups, self.key.as_ref())?;
+        self.stamp = FileStamp::read(&self.file_path)?;
+        self.base = self.customers.clone();
+        self.dirty = false;
+
+        Ok(())
+    }
+
+    fn has_unsaved_changes(&self) -> bool {
+        self.dirty
+    }
+
+    fn changed_externally(&mut self) -> io::Result<bool> {
+        match &self.stamp {
+            // A touched but otherwise identical file is not a change
+            Some(ours) => ours.differs(&self.file_path),
+            None => Ok(self.file_path.exists()),
+        }
+    }
+
+    fn merge_external(&mut self) -> io::Result<MergeResult> {
+        let stamp = FileStamp::read(&self.file_path)?;
+        let theirs = self.load_theirs()?;
+        let result = merge::three_way_merge(&self.base, &self.customers, &theirs);
+
+        self.customers = result.customers.clone();
+        self.dirty = self.customers != theirs;
+        self.base = theirs;
+        self.stamp = stamp;
+
+        Ok(result)
+    }
+
+    fn is_encrypted(&self) -> io::Result<bool> {
+        ContactsFile::is_encrypted(&self.file_path)
+    }
+
+    fn unlock(&mut self, passphrase: &str) -> io::Result<()> {
+        let contents = fs::read(&self.file_path)?;
+        self.key = Some(FileKey::for_file(&contents, passphrase)?);
+
+        Ok(())
+    }
+
+    fn lock(&mut self) -> io::Result<bool> {
+        if let Some(parent) = self.file_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).truncate(false).write(true).open(self.lock_path())?;
+        match file.try_lock_exclusive() {
+            Ok(_) => {
+                self.lock = Some(file);
+                Ok(true)
+            },
+            Err(e) if e.kind() == fs2::lock_contended_error().kind() => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    fn saved_storage(path: &Path) -> JsonStorage {
+        let mut storage = JsonStorage::new(path.to_path_buf(), 0);
+        let mut customer = Customer::new();
+        customer.set_company_name("Acme".to_string());
+        storage.insert(&customer).unwrap();
+        storage.flush().unwrap();
+        storage
+    }
+
+    fn set_modified(path: &Path, modified: SystemTime) {
+        OpenOptions::new().write(true).open(path).unwrap().set_modified(modified).unwrap();
+    }
+
+    #[test]
+    fn content_change_with_the_same_mtime_is_detected() {
+        let dir = TestDir::new("json_same_mtime");
+        let path = dir.join("contacts.json");
+        let mut storage = saved_storage(&path);
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+
+        // Same length too, only the hash can tell
+        let contents = fs::read_to_string(&path).unwrap().replace("Acme", "Acne");
+        fs::write(&path, contents).unwrap();
+        set_modified(&path, modified);
+
+        assert!(storage.changed_externally().unwrap());
+    }
+
+    #[test]
+    fn touched_file_is_not_a_change() {
+        let dir = TestDir::new("json_touched");
+        let path = dir.join("contacts.json");
+        let mut storage = saved_storage(&path);
+
+        set_modified(&path, SystemTime::now() + Duration::from_secs(60));
+        assert!(!storage.changed_externally().unwrap());
+
+        fs::remove_file(&path).unwrap();
+        assert!(storage.changed_externally().unwrap());
+    }
+
+    #[test]
+    fn edits_are_unsaved_until_flushed() {
+        let dir = TestDir::new("json_dirty");
+        let path = dir.join("contacts.json");
+        let mut storage = saved_storage(&path);
+        assert!(!storage.has_unsaved_changes());
+
+        let mut customer = storage.load().unwrap().remove(0);
+        assert!(!storage.has_unsaved_changes());
+        customer.set_company_name("Acme Pty".to_string());
+        storage.update(&customer).unwrap();
+        assert!(storage.has_unsaved_changes());
+        storage.flush().unwrap();
+        assert!(!storage.has_unsaved_changes());
+
+        storage.delete(customer.id).unwrap();
+        assert!(storage.has_unsaved_changes());
+        // Reloading drops the edit
+        storage.load().unwrap();
+        assert!(!storage.has_unsaved_changes());
+    }
+
+    #[test]
+    fn merge_is_unsaved_only_when_ours_differ() {
+        let dir = TestDir::new("json_dirty_merge");
+        let path = dir.join("contacts.json");
+        let mut storage = saved_storage(&path);
+        let mut other = JsonStorage::new(path.clone(), 0);
+        other.load().unwrap();
+        let mut globex = Customer::new();
+        globex.set_company_name("Globex".to_string());
+        other.insert(&globex).unwrap();
+        other.flush().unwrap();
+
+        // Nothing of ours to keep, the file already says it all
+        storage.merge_external().unwrap();
+        assert!(!storage.has_unsaved_changes());
+
+        let mut initech = Customer::new();
+        initech.set_company_name("Initech".to_string());
+        storage.insert(&initech).unwrap();
+        other.delete(globex.id).unwrap();
+        other.flush().unwrap();
+        storage.merge_external().unwrap();
+        assert!(storage.has_unsaved_changes());
+        assert_eq!(storage.query("").unwrap().len(), 2);
+    }
+}
@@ -3,6 +3,7 @@ use crossterm::style::{Colors, Print, SetColors};
 use crossterm::{ExecutableCommand, QueueableCommand};
 use crossterm::terminal::{Clear, ClearType};
 use std::io::{self, stdout, Write};
+use zeroize::{Zeroize, Zeroizing};
 
 use crate::colors::ColorScheme;
 
@@ -12,6 +13,7 @@ pub struct LineBuffer {
     caret_pos: usize,
     prompt: String,
     insert: bool,
+    masked: bool,
     color_scheme: ColorScheme
 }
 
@@ -22,6 +24,7 @@ impl LineBuffer {
             caret_pos: 0,
             prompt,
             insert: true,
+            masked: false,
             color_scheme
         }
     }
@@ -30,10 +33,22 @@ impl LineBuffer {
         self.buffer.clone()
     }
 
+    /// Takes the text out of the buffer, for passphrases. It is wiped from
+    /// memory once the caller drops it.
+    pub fn take_secret(&mut self) -> io::Result<Zeroizing<String>> {
+        let secret = Zeroizing::new(std::mem::take(&mut self.buffer));
+        self.caret_pos = 0;
+        self.draw()?;
+
+        Ok(secret)
+    }
+
     pub fn set_buffer(&mut self, buffer: String) -> io::Result<()> {
-        log::info!("Setting buffer to {}", buffer);
+        if !self.masked {
+            log::info!("Setting buffer to {}", buffer);
+        }
+        self.buffer.zeroize();
         self.buffer = buffer;
-        log::info!("Buffer is now {}", self.buffer);
         self.caret_pos = self.buffer.len();
         self.draw()?;
 
@@ -47,9 +62,25 @@ impl LineBuffer {
 
         Ok(())
     }
+    /// Shows `*` in place of the text, for passphrases.
+    pub fn set_masked(&mut self, masked: bool) -> io::Result<()> {
+        self.masked = masked;
+        self.draw()?;
+
+        Ok(())
+    }
+
+    fn display(&self, text: &str) -> String {
+        if self.masked {
+            "*".repeat(text.chars().count())
+        } else {
+            text.to_string()
+        }
+    }
+
     pub fn clear(&mut self) -> io::Result<()> {
         log::info!("Clearing buffer");
-        self.buffer = String::new();
+        self.buffer.zeroize();
         self.caret_pos = 0;
         self.draw()?;
 
@@ -61,7 +92,7 @@ impl LineBuffer {
         self.set_colors()?;
         stdout().queue(Clear(ClearType::CurrentLine))?;
         stdout().queue(Print(&self.prompt))?;
-        stdout().queue(Print(&self.buffer))?;
+        stdout().queue(Print(self.display(&self.buffer)))?;
         self.sync_caret()?;
 
         Ok(())
@@ -91,12 +122,12 @@ impl LineBuffer {
             self.caret_pos += text.chars().count();
 
             // Print the inserted text
-            stdout().queue(Print(text))?;
+            stdout().queue(Print(self.display(text)))?;
 
             // Print the rest of the buffer after the inserted text
             if self.caret_pos < chars.len() {
                 let rest: String = chars[self.caret_pos..].iter().collect();
-                stdout().queue(Print(rest))?;
+                stdout().queue(Print(self.display(&rest)))?;
             }
         } else {
             // In overtype mode, remove the existing text and replace it with the new text
@@ -110,10 +141,13 @@ impl LineBuffer {
             self.caret_pos += text.chars().count();
 
             // Print the replaced text
-            stdout().queue(Print(text))?;
+            stdout().queue(Print(self.display(text)))?;
         }
 
-        self.buffer = chars.into_iter().collect();
+        // The old text may be a passphrase, wipe it rather than just drop it
+        let mut old = std::mem::replace(&mut self.buffer, chars.iter().collect());
+        old.zeroize();
+        chars.zeroize();
 
         stdout().queue(Clear(ClearType::UntilNewLine))?;
         stdout().flush()?;
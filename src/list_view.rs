@@ -0,0 +1,112 @@
+use crate::colors::ColorScheme;
+use std::io::{self, Write, stdout};
+use crossterm::cursor::{SavePosition, RestorePosition, MoveTo, MoveToNextLine};
+use crossterm::style::{Print, SetColors, Colors};
+use crossterm::terminal::{size, Clear, ClearType};
+use crossterm::QueueableCommand;
+
+/// A read-only list drawn over the scroll buffer area, used by the views that
+/// show something other than the customer list (history, trash and so on).
+pub struct ListView {
+    title: String,
+    items: Vec<String>,
+    pos: usize,
+    rows: usize,
+    cols: usize,
+    color_scheme: ColorScheme,
+}
+
+impl ListView {
+    pub fn new(color_scheme: ColorScheme) -> Result<Self, io::Error> {
+        let size = size()?;
+        let (cols, rows) = (size.0 as usize, size.1 as usize - 2);
+
+        Ok(ListView {
+            title: String::new(),
+            items: Vec::new(),
+            pos: 0,
+            rows,
+            cols,
+            color_scheme,
+        })
+    }
+
+    pub fn set_items(&mut self, title: String, items: Vec<String>) -> io::Result<()> {
+        self.title = title;
+        self.items = items;
+        self.pos = 0;
+        self.draw()?;
+
+        Ok(())
+    }
+
+    /// Replaces the items keeping the selection, for lists changed in place.
+    pub fn update_items(&mut self, title: String, items: Vec<String>) -> io::Result<()> {
+        self.title = title;
+        self.items = items;
+        self.pos = self.pos.min(self.items.len().saturating_sub(1));
+        self.draw()?;
+
+        Ok(())
+    }
+
+    pub fn get_selected_index(&self) -> Option<usize> {
+        if self.items.is_empty() {
+            None
+        } else {
+            Some(self.pos)
+        }
+    }
+
+    pub fn draw(&self) -> io::Result<()> {
+        stdout().queue(SavePosition)?;
+        stdout().queue(MoveTo(0, 1))?;
+        stdout().queue(SetColors(Colors::new(self.color_scheme.cyan, self.color_scheme.dark_black)))?;
+        stdout().queue(Clear(ClearType::CurrentLine))?;
+        stdout().queue(Print(self.title.chars().take(self.cols).collect::<String>()))?;
+        stdout().queue(MoveToNextLine(1))?;
+
+        // One row is taken by the title
+        let rows = self.rows.saturating_sub(1);
+        let start_index = if self.pos < rows { 0 } else { self.pos + 1 - rows };
+        let end_index = (start_index + rows).min(self.items.len());
+
+        for i in 0..rows {
+            let index = start_index + i;
+            if index == self.pos && index < end_index {
+                stdout().queue(SetColors(Colors::new(self.color_scheme.dark_black, self.color_scheme.magenta)))?;
+            } else {
+                stdout().queue(SetColors(Colors::new(self.color_scheme.magenta, self.color_scheme.dark_black)))?;
+            }
+            stdout().queue(Clear(ClearType::CurrentLine))?;
+            if index < end_index {
+                stdout().queue(Print(self.items[index].chars().take(self.cols).collect::<String>()))?;
+            } else if index == 0 {
+                stdout().queue(Print("(empty)"))?;
+            }
+            stdout().queue(MoveToNextLine(1))?;
+        }
+        stdout().queue(RestorePosition)?;
+        stdout().flush()?;
+
+        Ok(())
+    }
+
+    pub fn move_up(&mut self) -> io::Result<()> {
+        if self.pos > 0 {
+            self.pos -= 1;
+        }
+        self.draw()?;
+
+        Ok(())
+    }
+
+    pub fn move_down(&mut self) -> io::Result<()> {
+        if self.pos + 1 < self.items.len() {
+            self.pos += 1;
+        }
+        self.draw()?;
+
+        Ok(())
+    }
+}
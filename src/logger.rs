@@ -8,6 +8,7 @@ pub fn setup_logger() -> Result<(), Error> {
     let log_path = proj_dirs.config_dir().join("log.log");
     fs::create_dir_all("log")?;
     let log_file = File::create(log_path)?;
+    #[allow(clippy::io_other_error)]
     WriteLogger::init(LevelFilter::Info, Config::default(), log_file).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
 
     Ok(())
@@ -1,15 +1,34 @@
+mod call_log;
 mod colors;
+mod commands;
+mod contacts_file;
+mod crypto;
+mod csv_export;
+mod csv_import;
 mod line_buffer;
+mod list_view;
 mod status_line;
 mod utils;
 mod scroll_buffer;
 mod editor;
+mod grandstream;
 mod customer;
+mod duplicates;
+mod custom_field;
 mod logger;
+mod merge;
 mod phone;
+mod undo;
+mod storage;
+mod json_storage;
+mod sqlite_storage;
+mod vcard;
+#[cfg(test)]
+mod test_dir;
 
 use editor::Editor;
-use clap::Parser;
+use storage::StorageKind;
+use clap::{Parser, Subcommand};
 use directories::ProjectDirs;
 use std::path::PathBuf;
 
@@ -24,7 +43,10 @@ fn main() {
 
     match run_program() {
         Ok(_) => log::info!("Program exited successfully"),
-        Err(e) => log::error!("Program failed: {}", e),
+        Err(e) => {
+            log::error!("Program failed: {}", e);
+            eprintln!("Error: {}", e);
+        },
     }
 }
 
@@ -33,12 +55,7 @@ fn run_program() -> Result<(), Box<dyn std::error::Error>> {
 
     let args = Args::parse();
 
-    let file_path = args.filename
-        .map(PathBuf::from)
-        .unwrap_or_else(|| {
-            let proj_dirs = ProjectDirs::from("au", "popplestones", "RustyCrm").expect("Failed to get project directory");
-            proj_dirs.config_dir().join("contacts.json")
-        });
+    let file_path = args.filename.map(PathBuf::from);
 
     let config_path = args.config
         .map(PathBuf::from)
@@ -47,7 +64,45 @@ fn run_program() -> Result<(), Box<dyn std::error::Error>> {
             proj_dirs.config_dir().join("config.toml")
         });
 
-    let mut editor = Editor::new(file_path, config_path, args.no_splash, args.sample_data)?;
+    match args.command {
+        Some(Command::Convert { source, destination, from, to }) => {
+            return commands::convert(PathBuf::from(source), PathBuf::from(destination), from, to, &config_path);
+        },
+        Some(Command::List { query }) => {
+            return commands::list(query, file_path, args.backend, &config_path);
+        },
+        Some(Command::ImportVcard { source }) => {
+            return commands::import_vcard(PathBuf::from(source), file_path, args.backend, &config_path);
+        },
+        Some(Command::ExportVcard { query, output }) => {
+            return commands::export_vcard(query, output.map(PathBuf::from), file_path, args.backend, &config_path);
+        },
+        Some(Command::ImportCsv { source, delimiter, encoding, map, yes }) => {
+            return commands::import_csv(PathBuf::from(source), delimiter, encoding, map, yes,
+                                        file_path, args.backend, &config_path);
+        },
+        Some(Command::ExportCsv { query, output, columns }) => {
+            return commands::export_csv(query, output.map(PathBuf::from), columns, file_path, args.backend, &config_path);
+        },
+        Some(Command::ImportGrandstream { source }) => {
+            return commands::import_grandstream(PathBuf::from(source), file_path, args.backend, &config_path);
+        },
+        Some(Command::ExportGrandstream { query, output }) => {
+            return commands::export_grandstream(query, output.map(PathBuf::from), file_path, args.backend, &config_path);
+        },
+        Some(Command::Encrypt { file }) => {
+            return commands::encrypt(json_path(file, file_path));
+        },
+        Some(Command::Decrypt { file }) => {
+            return commands::decrypt(json_path(file, file_path), &config_path);
+        },
+        Some(Command::ChangePassphrase { file }) => {
+            return commands::change_passphrase(json_path(file, file_path), &config_path);
+        },
+        None => {}
+    }
+
+    let mut editor = Editor::new(file_path, args.backend, config_path, args.no_splash, args.sample_data)?;
 
     editor.init()?;
 
@@ -56,6 +111,14 @@ fn run_program() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+/// The JSON contacts file a subcommand works on, the default one when
+/// neither the argument nor `--filename` is given.
+fn json_path(file: Option<String>, file_path: Option<PathBuf>) -> PathBuf {
+    file.map(PathBuf::from)
+        .or(file_path)
+        .unwrap_or_else(|| StorageKind::Json.default_path())
+}
+
 #[derive(Parser, Debug)]
 #[clap(version = "1.0", author = "Shane Poppleton")]
 struct Args {
@@ -70,5 +133,92 @@ struct Args {
 
     #[clap(long)]
     sample_data: bool,
+
+    /// Storage backend, overrides `backend` in config.toml
+    #[clap(short, long, value_enum)]
+    backend: Option<StorageKind>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Copy every customer from one store into another, e.g. contacts.json to contacts.db
+    Convert {
+        source: String,
+        destination: String,
+        /// Backend of the source, guessed from the extension when not given
+        #[clap(long, value_enum)]
+        from: Option<StorageKind>,
+        /// Backend of the destination, guessed from the extension when not given
+        #[clap(long, value_enum)]
+        to: Option<StorageKind>,
+    },
+    /// Print the customers matching a query
+    List {
+        query: Option<String>,
+    },
+    /// Add the contacts in a vCard (.vcf) file, skipping ones we already have
+    ImportVcard {
+        source: String,
+    },
+    /// Write the customers matching a query, or all of them, as vCards
+    ExportVcard {
+        query: Option<String>,
+        /// The .vcf file to write, stdout when not given
+        #[clap(short, long)]
+        output: Option<String>,
+    },
+    /// Add the rows of a CSV file as customers, after showing the column
+    /// mapping and a preview
+    ImportCsv {
+        source: String,
+        /// The delimiter, e.g. ";" or "tab", detected when not given
+        #[clap(short, long)]
+        delimiter: Option<String>,
+        /// The encoding, e.g. windows-1252, detected when not given
+        #[clap(short, long)]
+        encoding: Option<String>,
+        /// Map a column, by header or number, to a field or "ignore", e.g. -m "Tel 2=phone"
+        #[clap(short, long)]
+        map: Vec<String>,
+        /// Import without asking for confirmation
+        #[clap(short, long)]
+        yes: bool,
+    },
+    /// Write the customers matching a query, or all of them, as CSV
+    ExportCsv {
+        query: Option<String>,
+        /// The .csv file to write, stdout when not given
+        #[clap(short, long)]
+        output: Option<String>,
+        /// Field names in column order, e.g. "company,contact,phones", overrides csv_columns in config.toml
+        #[clap(long)]
+        columns: Vec<String>,
+    },
+    /// Add the contacts in a Grandstream XML phonebook, skipping ones we already have
+    ImportGrandstream {
+        source: String,
+    },
+    /// Write the customers matching a query, or all of them, as a Grandstream XML phonebook
+    ExportGrandstream {
+        query: Option<String>,
+        /// The phonebook .xml file to write, stdout when not given
+        #[clap(short, long)]
+        output: Option<String>,
+    },
+    /// Encrypt a plain JSON contacts file with a passphrase
+    Encrypt {
+        file: Option<String>,
+    },
+    /// Turn an encrypted contacts file back into plain JSON
+    Decrypt {
+        file: Option<String>,
+    },
+    /// Re-encrypt a contacts file with a new passphrase
+    ChangePassphrase {
+        file: Option<String>,
+    },
 }
 
@@ -0,0 +1,165 @@
+use crate::customer::Customer;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// A record changed on both sides. The merge keeps one version, applying
+/// `alternative` switches to the other one, `None` meaning the record
+/// should be deleted.
+#[derive(Debug, Clone)]
+pub struct Conflict {
+    pub id: Uuid,
+    pub name: String,
+    pub description: String,
+    pub alternative: Option<Customer>,
+}
+
+#[derive(Debug)]
+pub struct MergeResult {
+    pub customers: Vec<Customer>,
+    pub conflicts: Vec<Conflict>,
+}
+
+/// Merges our changes and the changes made on disk (`theirs`) since both
+/// started from `base`, record by record. When a record changed on both
+/// sides the local version is kept and a conflict is reported, a change
+/// always wins over a delete so nothing is lost silently.
+pub fn three_way_merge(base: &[Customer], ours: &[Customer], theirs: &[Customer]) -> MergeResult {
+    let base: HashMap<Uuid, &Customer> = base.iter().map(|c| (c.id, c)).collect();
+    let ours_by_id: HashMap<Uuid, &Customer> = ours.iter().map(|c| (c.id, c)).collect();
+    let theirs_by_id: HashMap<Uuid, &Customer> = theirs.iter().map(|c| (c.id, c)).collect();
+
+    let mut ids: Vec<Uuid> = ours.iter().map(|c| c.id).collect();
+    ids.extend(theirs.iter().map(|c| c.id).filter(|id| !ours_by_id.contains_key(id)));
+    ids.extend(base.keys().filter(|id| !ours_by_id.contains_key(id) && !theirs_by_id.contains_key(id)));
+
+    let mut customers = Vec::new();
+    let mut conflicts = Vec::new();
+
+    for id in ids {
+        let b = base.get(&id).copied();
+        let o = ours_by_id.get(&id).copied();
+        let t = theirs_by_id.get(&id).copied();
+
+        match (b, o, t) {
+            (_, Some(o), Some(t)) if o == t => customers.push(o.clone()),
+            (Some(b), Some(o), Some(t)) if b == o => customers.push(t.clone()),
+            (Some(b), Some(o), Some(t)) if b == t => customers.push(o.clone()),
+            (_, Some(o), Some(t)) => {
+                customers.push(o.clone());
+                conflicts.push(Conflict {
+                    id,
+                    name: o.name.clone(),
+                    description: "changed here and on disk, kept the local version".to_string(),
+                    alternative: Some(t.clone()),
+                });
+            },
+            (None, Some(o), None) => customers.push(o.clone()),
+            (None, None, Some(t)) => customers.push(t.clone()),
+            (Some(b), Some(o), None) => {
+                if b != o {
+                    customers.push(o.clone());
+                    conflicts.push(Conflict {
+                        id,
+                        name: o.name.clone(),
+                        description: "deleted on disk but changed here, kept it".to_string(),
+                        alternative: None,
+                    });
+                }
+            },
+            (Some(b), None, Some(t)) => {
+                if b != t {
+                    customers.push(t.clone());
+                    conflicts.push(Conflict {
+                        id,
+                        name: t.name.clone(),
+                        description: "deleted here but changed on disk, kept it".to_string(),
+                        alternative: None,
+                    });
+                }
+            },
+            (_, None, None) => {},
+        }
+    }
+
+    Customer::sort(&mut customers);
+
+    MergeResult { customers, conflicts }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::slice;
+
+    fn company(name: &str) -> Customer {
+        let mut customer = Customer::new();
+        customer.set_company_name(name.to_string());
+        customer
+    }
+
+    fn renamed(customer: &Customer, name: &str) -> Customer {
+        let mut customer = customer.clone();
+        customer.set_company_name(name.to_string());
+        customer
+    }
+
+    fn names(result: &MergeResult) -> Vec<&str> {
+        let mut names: Vec<&str> = result.customers.iter().map(|c| c.name.as_str()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn change_on_one_side_is_taken() {
+        let acme = company("Acme");
+        let bolt = company("Bolt");
+        let base = vec![acme.clone(), bolt.clone()];
+
+        let result = three_way_merge(&base, &[renamed(&acme, "Acme Ltd"), bolt.clone()], &[acme.clone(), renamed(&bolt, "Bolt Co")]);
+        assert_eq!(names(&result), ["Acme Ltd", "Bolt Co"]);
+        assert!(result.conflicts.is_empty());
+    }
+
+    #[test]
+    fn change_on_both_sides_keeps_ours() {
+        let acme = company("Acme");
+
+        let result = three_way_merge(slice::from_ref(&acme), &[renamed(&acme, "Ours")], &[renamed(&acme, "Theirs")]);
+        assert_eq!(names(&result), ["Ours"]);
+        assert_eq!(result.conflicts.len(), 1);
+        assert_eq!(result.conflicts[0].alternative.as_ref().map(|c| c.name.as_str()), Some("Theirs"));
+    }
+
+    #[test]
+    fn change_wins_over_delete() {
+        let acme = company("Acme");
+        let bolt = company("Bolt");
+        let base = vec![acme.clone(), bolt.clone()];
+
+        // Acme deleted on disk but changed here, Bolt deleted here but changed on disk
+        let result = three_way_merge(&base, &[renamed(&acme, "Acme Ltd")], &[renamed(&bolt, "Bolt Co")]);
+        assert_eq!(names(&result), ["Acme Ltd", "Bolt Co"]);
+        assert_eq!(result.conflicts.len(), 2);
+        assert!(result.conflicts.iter().all(|c| c.alternative.is_none()));
+    }
+
+    #[test]
+    fn unchanged_delete_is_taken() {
+        let acme = company("Acme");
+        let bolt = company("Bolt");
+        let base = vec![acme.clone(), bolt.clone()];
+
+        let result = three_way_merge(&base, slice::from_ref(&bolt), slice::from_ref(&acme));
+        assert!(result.customers.is_empty());
+        assert!(result.conflicts.is_empty());
+    }
+
+    #[test]
+    fn additions_on_both_sides_are_kept() {
+        let acme = company("Acme");
+
+        let result = three_way_merge(slice::from_ref(&acme), &[acme.clone(), company("Ours")], &[acme.clone(), company("Theirs")]);
+        assert_eq!(names(&result), ["Acme", "Ours", "Theirs"]);
+        assert!(result.conflicts.is_empty());
+    }
+}
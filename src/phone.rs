@@ -1,4 +1,5 @@
 use serde::{Serialize, Deserialize};
+use std::io;
 
 pub struct Phone {
     address: String,
@@ -37,6 +38,7 @@ impl Phone {
         }
     }
 
+    #[allow(dead_code)]
     pub async fn get_line_status(&self) -> Option<String> {
         if let Some(client) = &self.client {
             let url = format!("https://{}/{}{}?passcode={}",
@@ -52,12 +54,16 @@ impl Phone {
         None
     }
 
+    #[allow(dead_code)]
     pub fn get_phone_status(&self) {}
 
+    #[allow(dead_code)]
     pub fn phone_operation(&self, _operation: PhoneOperation) {}
 
+    #[allow(dead_code)]
     pub fn system_operation(&self, _operation: SystemOperation) {}
 
+    #[allow(dead_code)]
     pub fn send_key(&self, _key: PhoneKey) {}
 
     fn keypad_key_to_string(&self, key: KeypadKey) -> String {
@@ -76,7 +82,8 @@ impl Phone {
         }
     }
 
-    pub fn send_keys(&self, keys: Vec<PhoneKey>) {
+    /// Posts the keys one at a time, stopping at the first the phone refuses.
+    pub fn send_keys(&self, keys: Vec<PhoneKey>) -> io::Result<()> {
         // Make a post request, sending the keys
         if let Some(client) = &self.client {
             let url = format!("https://{}/{}{}",
@@ -93,13 +100,19 @@ impl Phone {
 
                 let params = [("password", self.password.clone()), ("keys", key.to_owned())];
 
-                client.post(&url).form(&params).send().expect("Got an error posting to endpoint");
+                client.post(&url).form(&params).send()
+                    .and_then(|response| response.error_for_status())
+                    .map_err(io::Error::other)?;
             }
+            Ok(())
+        } else {
+            Err(io::Error::other("the HTTP client could not be built"))
         }
     }
 
 }
 
+#[allow(dead_code)]
 pub enum PhoneOperation {
     EndCall,
     HoldCall,
@@ -108,11 +121,13 @@ pub enum PhoneOperation {
     Cancel
 }
 
+#[allow(dead_code)]
 pub enum SystemOperation {
     Reboot,
     Reset
 }
 
+#[allow(dead_code)]
 pub enum PhoneKey {
     Speaker,
     Transfer,
@@ -141,6 +156,7 @@ pub enum PhoneKey {
     Right,
 }
 
+#[allow(dead_code)]
 pub enum MultiPurposeKey {
     Key1,
     Key2,
@@ -168,6 +184,7 @@ pub enum MultiPurposeKey {
     Key24
 }
 
+#[allow(dead_code)]
 pub enum SoftKey {
     Key1,
     Key2,
@@ -189,6 +206,13 @@ pub enum PhoneLine {
     Line8
 }
 
+impl PhoneLine {
+    /// The account the line belongs to, numbered from 1 as on the handset.
+    pub fn account(&self) -> u8 {
+        *self as u8 + 1
+    }
+}
+
 pub enum KeypadKey {
     Zero,
     One,
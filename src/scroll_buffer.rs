@@ -1,61 +1,245 @@
+use crate::call_log::{CallLog, CallRecord};
 use crate::colors::ColorScheme;
-use crate::customer::Customer;
+use crate::customer::{Customer, Task};
+use crate::custom_field::CustomFieldDef;
+use crate::duplicates;
 use std::io::{self, Write, stdout};
 use crossterm::cursor::{SavePosition, RestorePosition, MoveTo, MoveToNextLine};
 use crossterm::style::{Print, SetColors, Colors };
 use crossterm::terminal::{size, Clear, ClearType};
 use crossterm::QueueableCommand;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use serde::{Deserialize, Serialize};
 use crate::phone::*;
+use crate::storage::{Storage, StorageKind};
+use crate::merge::Conflict;
+use chrono::{DateTime, Local, TimeDelta, Utc};
+use std::cmp::Reverse;
+use std::time::Duration;
+use uuid::Uuid;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Config {
     pub phone_ip: String,
     pub password: String,
-    pub line: PhoneLine
+    pub line: PhoneLine,
+    #[serde(default)]
+    pub custom_fields: Vec<CustomFieldDef>,
+    /// How many rotating backups of the contacts file to keep
+    #[serde(default = "default_backups")]
+    pub backups: usize,
+    #[serde(default)]
+    pub backend: StorageKind,
+    /// Days a deleted customer stays in the trash, 0 keeps them forever
+    #[serde(default = "default_trash_days")]
+    pub trash_days: u32,
+    /// Save after this many seconds without a key press, 0 turns it off
+    #[serde(default)]
+    pub autosave_seconds: u64,
+    /// Save after this many changes, 0 turns it off
+    #[serde(default)]
+    pub autosave_edits: usize,
+    /// Columns of a CSV export by field name, in order, every field when empty
+    #[serde(default)]
+    pub csv_columns: Vec<String>,
+    /// Handset account per phone label in the Grandstream phonebook, e.g.
+    /// `mobile = 2`, other numbers use the account of `line`
+    #[serde(default)]
+    pub grandstream_accounts: BTreeMap<String, u8>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            phone_ip: "".to_string(),
+            password: "".to_string(),
+            line: PhoneLine::Line1,
+            custom_fields: Vec::new(),
+            backups: default_backups(),
+            backend: StorageKind::default(),
+            trash_days: default_trash_days(),
+            autosave_seconds: 0,
+            autosave_edits: 0,
+            csv_columns: Vec::new(),
+            grandstream_accounts: BTreeMap::new(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(config_path: &Path) -> Result<Config, Box<dyn std::error::Error>> {
+        let contents = std::fs::read_to_string(config_path)?;
+        let config: Config = toml::from_str(&contents)?;
+        for field in config.custom_fields.iter() {
+            field.check()?;
+        }
+
+        Ok(config)
+    }
+
+    /// Whether to autosave after `idle` without a key press and `edits`
+    /// changes since the last attempt.
+    pub fn autosave_due(&self, idle: Duration, edits: usize) -> bool {
+        (self.autosave_seconds > 0 && idle >= Duration::from_secs(self.autosave_seconds))
+            || (self.autosave_edits > 0 && edits >= self.autosave_edits)
+    }
+}
+
+fn default_backups() -> usize {
+    5
+}
+
+fn default_trash_days() -> u32 {
+    30
+}
+
+/// The customers deleted more than `days` before `now`, `None` when that
+/// is too long ago for a date.
+fn expired_trash(trash: &[Customer], days: u32, now: DateTime<Utc>) -> Option<Vec<Uuid>> {
+    let cutoff = now.checked_sub_signed(TimeDelta::try_days(days.into())?)?;
+
+    Some(trash.iter()
+        .filter(|c| c.deleted_at.map(|d| d < cutoff).unwrap_or(false))
+        .map(|c| c.id)
+        .collect())
 }
 
 pub struct ScrollBuffer {
     buffer: Vec<Customer>,
+    trash: Vec<Customer>,
     config: Config,
     filter: String,
+    /// The customers matching the filter
+    matched: Vec<usize>,
+    /// The rows shown, each company followed by its people when expanded
     filtered: Vec<usize>,
+    /// People per company, counted over the whole buffer
+    people_counts: HashMap<Uuid, usize>,
+    expanded: HashSet<Uuid>,
+    favourites_only: bool,
+    active_tags: Vec<String>,
     scroll_pos: usize,
     rows: usize,
     cols: usize,
     color_scheme: ColorScheme,
-    phone: Option<Phone>
+    phone: Option<Phone>,
+    storage: Option<Box<dyn Storage>>,
+    calls: CallLog,
+    edits: usize,
 }
 
 
 impl ScrollBuffer {
     pub fn new(color_scheme: ColorScheme) -> Result<Self, io::Error> {
         let size = size()?;
-        let (cols, rows) = (size.0 as usize, size.1 as usize - 2);
 
-        Ok(ScrollBuffer {
+        Ok(ScrollBuffer::with_size(color_scheme, size.0 as usize, size.1 as usize - 2))
+    }
+
+    fn with_size(color_scheme: ColorScheme, cols: usize, rows: usize) -> ScrollBuffer {
+        ScrollBuffer {
             buffer: Vec::new(),
-            config: Config {
-                phone_ip: "".to_string(),
-                password: "".to_string(),
-                line: PhoneLine::Line1
-            },
+            trash: Vec::new(),
+            config: Config::default(),
+            matched: Vec::new(),
             filtered: Vec::new(),
+            people_counts: HashMap::new(),
+            expanded: HashSet::new(),
+            favourites_only: false,
             filter: String::new(),
+            active_tags: Vec::new(),
             scroll_pos: 0,
             cols,
             rows,
             color_scheme,
-            phone: None
-        })
+            phone: None,
+            storage: None,
+            calls: CallLog::new(),
+            edits: 0,
+        }
     }
 
-    pub fn delete_customer(&mut self) -> io::Result<()> {
-        self.buffer.remove(self.scroll_pos);
+    /// Moves the customer to the trash, returning the record as it was
+    /// before it was deleted. A company and its people are deleted at the
+    /// same `deleted_at`, which is how the trash knows they go together.
+    pub fn delete_customer(&mut self, id: Uuid, deleted_at: DateTime<Utc>) -> io::Result<Option<Customer>> {
+        let mut deleted = match self.get_customer(id) {
+            Some(customer) => customer.clone(),
+            None => return Ok(None),
+        };
+        deleted.deleted_at = Some(deleted_at);
+        let before = self.restore_customer(deleted)?;
         self.set_filter(self.filter.clone())?;
 
-        Ok(())
+        Ok(before)
+    }
+
+    /// Takes a customer back out of the trash, returning the trashed record.
+    pub fn restore_from_trash(&mut self, id: Uuid) -> io::Result<Option<Customer>> {
+        let mut restored = match self.trash.iter().find(|c| c.id == id) {
+            Some(customer) => customer.clone(),
+            None => return Ok(None),
+        };
+        restored.deleted_at = None;
+        let before = self.restore_customer(restored)?;
+        self.set_filter(self.filter.clone())?;
+
+        Ok(before)
+    }
+
+    /// The people that went to the trash together with a company.
+    pub fn trashed_people_of(&self, company_id: Uuid) -> Vec<Uuid> {
+        let deleted_at = match self.trash.iter().find(|c| c.id == company_id) {
+            Some(company) => company.deleted_at,
+            None => return Vec::new(),
+        };
+        self.trash.iter()
+            .filter(|c| c.company_id == Some(company_id) && c.deleted_at == deleted_at)
+            .map(|c| c.id)
+            .collect()
+    }
+
+    /// Deletes a customer for good, whether it is in the trash or not.
+    pub fn purge(&mut self, id: Uuid) -> io::Result<Option<Customer>> {
+        if let Some(storage) = self.storage.as_mut() {
+            storage.delete(id)?;
+        }
+        let purged = match self.find_customer(id) {
+            Some(index) => Some(self.buffer.remove(index)),
+            None => self.trash.iter().position(|c| c.id == id).map(|index| self.trash.remove(index)),
+        };
+        self.edits += 1;
+        self.set_filter(self.filter.clone())?;
+
+        Ok(purged)
+    }
+
+    /// Purges everything that has been in the trash longer than the
+    /// configured number of days, returning how many were purged.
+    pub fn purge_expired_trash(&mut self) -> io::Result<usize> {
+        if self.config.trash_days == 0 {
+            return Ok(0);
+        }
+        let expired = match expired_trash(&self.trash, self.config.trash_days, Utc::now()) {
+            Some(expired) => expired,
+            None => {
+                log::warn!("trash_days = {} reaches back before any date, nothing is purged", self.config.trash_days);
+                return Ok(0);
+            }
+        };
+        for id in expired.iter() {
+            self.purge(*id)?;
+        }
+
+        Ok(expired.len())
+    }
+
+    /// The deleted customers, most recently deleted first.
+    pub fn get_trash(&self) -> Vec<&Customer> {
+        let mut trash: Vec<&Customer> = self.trash.iter().collect();
+        trash.sort_by_key(|c| Reverse(c.deleted_at));
+        trash
     }
 
     pub fn splash_screen(&mut self) -> io::Result<()> {
@@ -66,13 +250,29 @@ impl ScrollBuffer {
         stdout().queue(MoveToNextLine(2))?;
         stdout().queue(Print("Shortcut Keys:"))?;
         stdout().queue(MoveToNextLine(1))?;
-        stdout().queue(Print(" Ctrl+Q -> Quit Program"))?;
+        stdout().queue(Print(" Ctrl+Q -> Quit Program, Ctrl+X -> Quit Without Saving"))?;
         stdout().queue(MoveToNextLine(1))?;
         stdout().queue(Print(" Ctrl+A -> Add Customer"))?;
         stdout().queue(MoveToNextLine(1))?;
         stdout().queue(Print(" Ctrl+E -> Edit Customer"))?;
         stdout().queue(MoveToNextLine(1))?;
-        stdout().queue(Print(" Ctrl+D -> Delete Customer"))?;
+        stdout().queue(Print(" Ctrl+D -> Delete Customer, Ctrl+R -> Trash"))?;
+        stdout().queue(MoveToNextLine(1))?;
+        stdout().queue(Print(" Ctrl+G -> Find And Merge Duplicates"))?;
+        stdout().queue(MoveToNextLine(1))?;
+        stdout().queue(Print(" Ctrl+P -> Add Contact To Company, Tab -> Show/Hide People"))?;
+        stdout().queue(MoveToNextLine(1))?;
+        stdout().queue(Print(" Ctrl+F -> Pin/Unpin Favourite, Ctrl+B -> Favourites Only"))?;
+        stdout().queue(MoveToNextLine(1))?;
+        stdout().queue(Print(" Ctrl+N -> Add Note, Ctrl+O -> Add Task, Ctrl+U -> Agenda"))?;
+        stdout().queue(MoveToNextLine(1))?;
+        stdout().queue(Print(" Ctrl+L -> Customer History, Ctrl+W -> Recent Calls"))?;
+        stdout().queue(MoveToNextLine(1))?;
+        stdout().queue(Print(" Ctrl+T -> Edit Tags (search with #tag)"))?;
+        stdout().queue(MoveToNextLine(1))?;
+        stdout().queue(Print(" Ctrl+Z -> Undo, Ctrl+Y -> Redo"))?;
+        stdout().queue(MoveToNextLine(1))?;
+        stdout().queue(Print(" Ctrl+K -> Command (import-vcard, export-vcard, import-csv, export-csv, import-grandstream, export-grandstream)"))?;
         stdout().queue(MoveToNextLine(2))?;
 
         stdout().queue(Print("Press SPACE to continue"))?;
@@ -81,61 +281,336 @@ impl ScrollBuffer {
         Ok(())
     }
 
-    pub fn add_customer(&mut self, customer: Customer) {
+    pub fn add_customer(&mut self, mut customer: Customer) -> io::Result<()> {
+        let now = Utc::now();
+        customer.created_at = now;
+        customer.updated_at = now;
+        if let Some(storage) = self.storage.as_mut() {
+            storage.insert(&customer)?;
+        }
         self.buffer.push(customer);
+        self.edits += 1;
+
+        Ok(())
+    }
+
+    /// Replaces the customer with the same id, or adds it when there is none.
+    /// Returns the version that was replaced.
+    pub fn update_customer(&mut self, mut customer: Customer) -> io::Result<Option<Customer>> {
+        customer.updated_at = Utc::now();
+        self.restore_customer(customer)
     }
 
-    pub fn update_customer(&mut self, customer: Customer) {
-        if self.get_selected_customer().is_some() {
-            self.buffer[self.filtered[self.scroll_pos]] = customer;
+    /// Stores `customer` exactly as given, timestamps included, returning the
+    /// version it replaced. Undo and redo use this to put records back, a
+    /// record with `deleted_at` set goes into the trash.
+    pub fn restore_customer(&mut self, customer: Customer) -> io::Result<Option<Customer>> {
+        if let Some(storage) = self.storage.as_mut() {
+            storage.update(&customer)?;
+        }
+        let before = match self.find_customer(customer.id) {
+            Some(index) => Some(self.buffer.remove(index)),
+            None => self.trash.iter().position(|c| c.id == customer.id).map(|index| self.trash.remove(index)),
+        };
+        if customer.deleted_at.is_some() {
+            self.trash.push(customer);
         } else {
             self.buffer.push(customer);
         }
+        self.edits += 1;
+
+        Ok(before)
+    }
+
+    /// Every customer not in the trash.
+    pub fn get_customers(&self) -> &[Customer] {
+        &self.buffer
+    }
+
+    /// Groups of customers that look like the same one, by name or phone
+    /// number, oldest first in each group.
+    pub fn find_duplicates(&self) -> Vec<Vec<&Customer>> {
+        duplicates::find_groups(&self.buffer).into_iter()
+            .map(|group| group.into_iter().map(|i| &self.buffer[i]).collect())
+            .collect()
+    }
+
+    /// The customers matching the current filter, people of collapsed
+    /// companies included.
+    pub fn get_filtered_customers(&self) -> Vec<&Customer> {
+        self.matched.iter().map(|i| &self.buffer[*i]).collect()
+    }
+
+    /// The tasks not done yet across every customer, by due time then
+    /// priority.
+    pub fn agenda(&self) -> Vec<(&Customer, &Task)> {
+        let mut tasks: Vec<(&Customer, &Task)> = self.buffer.iter()
+            .flat_map(|c| c.tasks.iter().filter(|t| !t.done).map(move |t| (c, t)))
+            .collect();
+        tasks.sort_by_key(|(_, t)| (t.due, t.priority));
+        tasks
+    }
+
+    /// The number of tasks past due across every customer.
+    pub fn overdue_count(&self) -> usize {
+        let now = Utc::now();
+        self.buffer.iter().map(|c| c.overdue_tasks(now)).sum()
+    }
+
+    /// Lists the customer when it is hidden under a collapsed company.
+    pub fn reveal(&mut self, id: Uuid) -> io::Result<()> {
+        if let Some(company_id) = self.get_customer(id).and_then(|c| c.company_id) {
+            self.expanded.insert(company_id);
+        }
+        self.set_filter(self.filter.clone())
+    }
+
+    /// The people belonging to a company.
+    pub fn people_of(&self, company_id: Uuid) -> Vec<&Customer> {
+        self.buffer.iter().filter(|c| c.company_id == Some(company_id)).collect()
+    }
+
+    /// The company record named `name`, ignoring case, other than `exclude`.
+    pub fn find_company(&self, name: &str, exclude: Option<Uuid>) -> Option<&Customer> {
+        let name = name.trim().to_lowercase();
+        self.buffer.iter()
+            .filter(|c| Some(c.id) != exclude)
+            .find(|c| c.is_company() && c.name.trim().to_lowercase() == name)
+    }
+
+    /// Shows or hides the people of the selected company. With a person
+    /// selected their company is collapsed and selected instead.
+    pub fn toggle_expanded(&mut self) -> io::Result<()> {
+        let Some(selected) = self.get_selected_customer() else { return Ok(()) };
+        match selected.company_id.filter(|id| self.people_counts.contains_key(id)) {
+            Some(company_id) => {
+                self.expanded.remove(&company_id);
+                self.set_filter(self.filter.clone())?;
+                self.select_customer(company_id)
+            },
+            None => {
+                let id = selected.id;
+                if !self.expanded.remove(&id) {
+                    self.expanded.insert(id);
+                }
+                self.set_filter(self.filter.clone())
+            },
+        }
+    }
+
+    /// Lists only the favourites, or everyone again. Returns whether only
+    /// favourites are listed now.
+    pub fn toggle_favourites_only(&mut self) -> io::Result<bool> {
+        self.favourites_only = !self.favourites_only;
+        self.set_filter(self.filter.clone())?;
+
+        Ok(self.favourites_only)
+    }
+
+    /// Shows the people of a company.
+    pub fn expand(&mut self, company_id: Uuid) -> io::Result<()> {
+        self.expanded.insert(company_id);
+        self.set_filter(self.filter.clone())
+    }
+
+    pub fn get_customer(&self, id: Uuid) -> Option<&Customer> {
+        self.find_customer(id).map(|index| &self.buffer[index])
+    }
+
+    fn find_customer(&self, id: Uuid) -> Option<usize> {
+        self.buffer.iter().position(|c| c.id == id)
     }
 
     pub fn load_sample_data(&mut self) {
         self.buffer = Customer::generate(1000);
+        Customer::link_companies(&mut self.buffer);
     }
 
-    pub fn load_customers(&mut self, file_path: PathBuf) {
-        match Customer::load_customers(file_path) {
-            Ok(customers) => {
-                self.buffer = customers;
+    pub fn open_storage(&mut self, kind: StorageKind, file_path: PathBuf) -> io::Result<()> {
+        let storage = kind.open(file_path.clone(), self.config.backups)?;
+        // A plain text log beside encrypted contacts would give away who
+        // was called, their calls are only kept for the session
+        self.calls = match storage.is_encrypted() {
+            Ok(false) => CallLog::open(CallLog::path_for(&file_path)),
+            _ => {
+                log::info!("{} is encrypted, calls are not written to disk", file_path.display());
+                CallLog::new()
             },
-            Err(e) => {
-                log::error!("Error loading customers: {}", e);
-            }
+        };
+        self.storage = Some(storage);
+
+        Ok(())
+    }
+
+    /// Returns false when another instance has the store open.
+    pub fn lock_storage(&mut self) -> io::Result<bool> {
+        match self.storage.as_mut() {
+            Some(storage) => storage.lock(),
+            None => Ok(true),
         }
     }
 
-    pub fn load_config(&mut self, config_path: PathBuf) -> Result<(), Box<dyn std::error::Error>> {
-        let contents = std::fs::read_to_string(config_path)?;
+    pub fn is_encrypted(&self) -> io::Result<bool> {
+        match self.storage.as_ref() {
+            Some(storage) => storage.is_encrypted(),
+            None => Ok(false),
+        }
+    }
 
-        self.config = toml::from_str(&contents)?;
+    pub fn unlock(&mut self, passphrase: &str) -> io::Result<()> {
+        match self.storage.as_mut() {
+            Some(storage) => storage.unlock(passphrase),
+            None => Ok(()),
+        }
+    }
+
+    pub fn changed_externally(&mut self) -> io::Result<bool> {
+        match self.storage.as_mut() {
+            Some(storage) => storage.changed_externally(),
+            None => Ok(false),
+        }
+    }
+
+    /// Merges the changes made to the store by someone else into the buffer
+    /// and returns the records that changed on both sides.
+    pub fn merge_external(&mut self) -> io::Result<Vec<Conflict>> {
+        match self.storage.as_mut() {
+            Some(storage) => {
+                let result = storage.merge_external()?;
+                self.set_customers(result.customers);
+                Ok(result.conflicts)
+            },
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub fn load_customers(&mut self) -> io::Result<()> {
+        if let Some(storage) = self.storage.as_mut() {
+            let customers = storage.load()?;
+            self.set_customers(customers);
+        }
+
+        Ok(())
+    }
+
+    fn set_customers(&mut self, customers: Vec<Customer>) {
+        (self.trash, self.buffer) = customers.into_iter().partition(|c| c.deleted_at.is_some());
+        self.edits = 0;
+    }
+
+    pub fn load_config(&mut self, config_path: PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+        self.config = Config::load(&config_path)?;
 
         log::info!("Loaded config: {:?}", self.config);
 
-        self.phone = Some(Phone::new(self.config.phone_ip.clone(), self.config.password.clone(), self.config.line));
+        // Without an address every dial attempt is logged as failed
+        self.phone = Some(&self.config.phone_ip)
+            .filter(|ip| !ip.trim().is_empty())
+            .map(|ip| Phone::new(ip.clone(), self.config.password.clone(), self.config.line));
 
         Ok(())
     }
-    pub fn save_customers(&mut self, file_path: PathBuf) -> io::Result<()> {
-        Customer::save_customers(&self.buffer, file_path)
+    pub fn get_config(&self) -> &Config {
+        &self.config
+    }
+    /// Whether the buffer holds changes that are not saved yet.
+    pub fn is_modified(&self) -> bool {
+        self.storage.as_ref().map(|s| s.has_unsaved_changes()).unwrap_or(false)
+    }
+
+    /// The number of changes made since the last load or save.
+    pub fn edits_since_save(&self) -> usize {
+        self.edits
+    }
+
+    pub fn save_customers(&mut self) -> io::Result<()> {
+        if let Some(storage) = self.storage.as_mut() {
+            storage.flush()?;
+        }
+        self.edits = 0;
+
+        Ok(())
     }
 
+    /// Filters the buffer by `filter`. Words starting with `#` select
+    /// customers carrying that tag, the rest is matched as free text.
     pub fn set_filter(&mut self, filter: String) -> io::Result<()> {
+        self.apply_filter(filter);
+        self.draw()
+    }
+
+    /// Matches and lays out the rows for `filter` without drawing them.
+    fn apply_filter(&mut self, filter: String) {
+        let selected = self.get_selected_id();
         self.filter = filter;
         let filter_clone = self.filter.clone().to_lowercase();
-        self.filtered = self.buffer.iter().enumerate().filter(|(_, c)| {
-            c.name.to_lowercase().contains(&filter_clone) ||
-            c.contact_name.as_ref().unwrap_or(&"".to_string()).to_lowercase().contains(&filter_clone) ||
-            c.phone.as_ref().unwrap_or(&"".to_string()).to_lowercase().contains(&filter_clone)
-        }).map(|(i, _)| i).collect();
-        
-        self.scroll_pos = 0;
-        self.draw()?;
+        let (tags, words): (Vec<&str>, Vec<&str>) = filter_clone.split_whitespace()
+            .partition(|w| w.starts_with('#'));
+        self.active_tags = tags.into_iter().filter_map(Customer::parse_tag).collect();
+        let text = if self.active_tags.is_empty() { filter_clone.clone() } else { words.join(" ") };
+
+        self.matched = self.buffer.iter().enumerate()
+            .filter(|(_, c)| self.active_tags.iter().all(|t| c.has_tag(t)))
+            .filter(|(_, c)| !self.favourites_only || c.pinned)
+            .filter(|(_, c)| c.matches(&text))
+            .map(|(i, _)| i).collect();
+        self.build_rows();
+
+        // Keep the selection on the same record while it is still listed
+        self.scroll_pos = selected
+            .and_then(|id| self.filtered.iter().position(|i| self.buffer[*i].id == id))
+            .unwrap_or(0);
+    }
 
-        Ok(())
+    /// Lays out the matches as rows. A company is followed by its people when
+    /// expanded, or by those that match when the company itself does not.
+    /// People whose company is gone are listed on their own. Favourites come
+    /// first, a pinned person on their own rather than under the company.
+    fn build_rows(&mut self) {
+        let companies: HashMap<Uuid, usize> = self.buffer.iter().enumerate()
+            .filter(|(_, c)| c.company_id.is_none())
+            .map(|(i, c)| (c.id, i))
+            .collect();
+        let mut people: HashMap<Uuid, Vec<usize>> = HashMap::new();
+        for (i, customer) in self.buffer.iter().enumerate() {
+            if let Some(company_id) = customer.company_id.filter(|id| companies.contains_key(id)) {
+                people.entry(company_id).or_default().push(i);
+            }
+        }
+        let matched: HashSet<usize> = self.matched.iter().copied().collect();
+
+        let mut pinned_rows = Vec::new();
+        let mut rows = Vec::new();
+        for (i, customer) in self.buffer.iter().enumerate() {
+            if customer.company_id.map(|id| companies.contains_key(&id)).unwrap_or(false) {
+                if customer.pinned && matched.contains(&i) {
+                    pinned_rows.push(i);
+                }
+                continue;
+            }
+            let company_matches = matched.contains(&i);
+            // The favourites view lists favourites alone
+            let expanded = self.expanded.contains(&customer.id) && !self.favourites_only;
+            let members: Vec<usize> = people.get(&customer.id).into_iter().flatten()
+                .copied()
+                .filter(|p| !self.buffer[*p].pinned)
+                .collect();
+            let shown: Vec<usize> = if expanded && company_matches {
+                members
+            } else if expanded || !company_matches {
+                members.into_iter().filter(|p| matched.contains(p)).collect()
+            } else {
+                Vec::new()
+            };
+            if company_matches || !shown.is_empty() {
+                let group = if customer.pinned { &mut pinned_rows } else { &mut rows };
+                group.push(i);
+                group.extend(shown);
+            }
+        }
+        self.filtered = pinned_rows;
+        self.filtered.extend(rows);
+        self.people_counts = people.into_iter().map(|(id, p)| (id, p.len())).collect();
     }
 
     pub fn clear(&self) -> io::Result<()> {
@@ -150,23 +625,58 @@ impl ScrollBuffer {
         Ok(())
     }
 
-    pub fn dial_customer(&self) -> io::Result<()> {
+    /// Dials one of the selected customer's numbers, `choice` indexes into
+    /// `Customer::phones`, when it is `None` the primary number is dialled.
+    /// Every attempt is added to the call log, failed ones too, and the
+    /// record is returned. `None` means there was no number to dial.
+    pub fn dial_customer(&mut self, choice: Option<usize>) -> io::Result<Option<CallRecord>> {
         log::info!("Dialling customer");
+        let mut dialled = None;
         if let Some(customer) = self.get_selected_customer() {
             log::info!("Dialling customer: {:?}", customer);
-            if let Some(phone) = &customer.phone {
-                log::info!("Dialling phone: {}", phone);
-                if let Some(p) = &self.phone {
-                    log::info!("Phone is initialized..., sending keys");
-                    p.send_keys(self.get_phone_keys(phone));
+            let number = match choice {
+                Some(index) => customer.phones.get(index),
+                None => customer.primary_phone(),
+            };
+            if let Some(number) = number {
+                log::info!("Dialling phone: {}", number);
+                let result = match &self.phone {
+                    Some(p) => {
+                        log::info!("Phone is initialized..., sending keys");
+                        self.get_phone_keys(&number.number).and_then(|keys| p.send_keys(keys))
+                    },
+                    None => Err(io::Error::new(io::ErrorKind::NotConnected, "phone not configured")),
+                };
+                let result = result.map_err(|e| e.to_string());
+                if let Err(e) = result.as_ref() {
+                    log::error!("Dialling {} failed: {}", number, e);
                 }
+                dialled = Some(CallRecord::new(customer.id, number.number.clone(), self.config.line, result));
             }
         }
+        if let Some(record) = dialled.as_ref() {
+            let logged = self.calls.record(record.clone());
+            self.draw()?;
+            logged?;
+        }
 
-        Ok(())
+        Ok(dialled)
+    }
+
+    /// The calls to one customer, newest first.
+    pub fn get_calls(&self, id: Uuid) -> Vec<&CallRecord> {
+        self.calls.for_customer(id)
+    }
+
+    /// The latest calls to anyone, newest first.
+    pub fn get_recent_calls(&self, count: usize) -> Vec<&CallRecord> {
+        self.calls.recent(count)
     }
 
-    pub fn get_phone_keys(&self, number: &str) -> Vec<PhoneKey> {
+    /// The keypad presses dialling `number`. Spacing and punctuation as in
+    /// `(07) 3123-4567` are skipped, anything else the keypad can not
+    /// enter is an error.
+    pub fn get_phone_keys(&self, number: &str) -> io::Result<Vec<PhoneKey>> {
         let mut keys = Vec::new();
 
         for c in number.chars() {
@@ -183,15 +693,15 @@ impl ScrollBuffer {
                 '9' => PhoneKey::KeypadKey(KeypadKey::Nine),
                 '*' => PhoneKey::KeypadKey(KeypadKey::Star),
                 '#' => PhoneKey::KeypadKey(KeypadKey::Hash),
-                ' ' => continue, // Skip spaces
-                _ => panic!("Invalid character in phone number: {}", c)
+                ' ' | '-' | '(' | ')' | '.' | '/' => continue,
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                               format!("can not dial '{}' in {}", c, number))),
             };
             keys.push(key);
         }
         keys.push(PhoneKey::Send);
 
-        keys
-
+        Ok(keys)
     }
     fn set_colors(&self) -> io::Result<()> {
         stdout().queue(SetColors(Colors::new(self.color_scheme.magenta, self.color_scheme.dark_black)))?;
@@ -224,7 +734,15 @@ impl ScrollBuffer {
                 stdout().queue(SetColors(Colors::new(self.color_scheme.magenta, self.color_scheme.dark_black)))?;
             }
             stdout().queue(Clear(ClearType::CurrentLine))?;
-            stdout().queue(Print(format!("{}", customer)))?;
+            let structure = match (customer.company_id, self.people_counts.get(&customer.id)) {
+                (Some(company_id), _) if self.people_counts.contains_key(&company_id) && !customer.pinned => "    ".to_string(),
+                (_, Some(count)) if self.expanded.contains(&customer.id) && !self.favourites_only => format!("▾ ({}) ", count),
+                (_, Some(count)) => format!("▸ ({}) ", count),
+                _ => String::new(),
+            };
+            let prefix = format!("{} {}", if customer.pinned { "★" } else { " " }, structure);
+            let width = self.cols.saturating_sub(1 + prefix.chars().count());
+            stdout().queue(Print(format!("{}{}", prefix, self.row_with_last_called(customer, width))))?;
             stdout().queue(MoveToNextLine(1))?;
         }
         stdout().queue(RestorePosition)?;
@@ -238,6 +756,20 @@ impl ScrollBuffer {
     }
 
 
+    /// The customer's row with when they were last called right aligned,
+    /// left out on narrow terminals.
+    fn row_with_last_called(&self, customer: &Customer, width: usize) -> String {
+        const COLUMN: usize = 18;
+        if width < COLUMN * 3 {
+            return customer.row(width);
+        }
+        let last_called = self.calls.last_called(customer.id)
+            .map(|t| t.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default();
+        let row = customer.row(width - COLUMN);
+        format!("{:<row_width$}{:>COLUMN$}", row, last_called, row_width = width - COLUMN)
+    }
+
     fn draw_scroll_bar(&self) -> io::Result<()> {
         stdout().queue(SavePosition)?;
 
@@ -269,7 +801,13 @@ impl ScrollBuffer {
         (proportion * (self.rows - 1) as f64).round() as usize + 1
     }
     pub fn get_results_count(&self) -> usize {
-        self.filtered.len()
+        self.matched.len()
+    }
+    /// The number of customers carrying each tag used in the current query.
+    pub fn get_tag_counts(&self) -> Vec<(String, usize)> {
+        self.active_tags.iter()
+            .map(|t| (t.clone(), self.buffer.iter().filter(|c| c.has_tag(t)).count()))
+            .collect()
     }
     pub fn move_up(&mut self) -> io::Result<()> {
         if self.scroll_pos > 0 {
@@ -280,13 +818,24 @@ impl ScrollBuffer {
         Ok(())
     }
     pub fn move_down(&mut self) -> io::Result<()> {
-        if self.scroll_pos < self.filtered.len() - 1 {
+        if self.scroll_pos + 1 < self.filtered.len() {
             self.scroll_pos += 1;
         }
         self.draw()?;
 
         Ok(())
     }
+    pub fn get_selected_id(&self) -> Option<Uuid> {
+        self.get_selected_customer().map(|c| c.id)
+    }
+    pub fn select_customer(&mut self, id: Uuid) -> io::Result<()> {
+        if let Some(pos) = self.filtered.iter().position(|i| self.buffer[*i].id == id) {
+            self.scroll_pos = pos;
+            self.draw()?;
+        }
+
+        Ok(())
+    }
     pub fn get_selected_customer(&self) -> Option<&Customer> {
         if ! self.filtered.is_empty() {
             let customer_index = self.filtered[self.scroll_pos];
@@ -296,3 +845,107 @@ impl ScrollBuffer {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deleted(days_ago: i64, now: DateTime<Utc>) -> Customer {
+        let mut customer = Customer::new();
+        customer.deleted_at = Some(now - TimeDelta::days(days_ago));
+        customer
+    }
+
+    #[test]
+    fn trash_expires_after_the_configured_days() {
+        let now = Utc::now();
+        let trash = vec![deleted(1, now), deleted(29, now), deleted(31, now), deleted(400, now)];
+
+        assert_eq!(expired_trash(&trash, 30, now), Some(vec![trash[2].id, trash[3].id]));
+        assert_eq!(expired_trash(&trash, 365, now), Some(vec![trash[3].id]));
+    }
+
+    #[test]
+    fn trash_days_too_large_for_a_date_purge_nothing() {
+        let now = Utc::now();
+        let trash = vec![deleted(31, now)];
+
+        assert_eq!(expired_trash(&trash, u32::MAX, now), None);
+    }
+
+    #[test]
+    fn autosave_waits_for_either_threshold() {
+        let config = Config { autosave_seconds: 30, autosave_edits: 5, ..Config::default() };
+        assert!(!config.autosave_due(Duration::from_secs(29), 4));
+        assert!(config.autosave_due(Duration::from_secs(30), 0));
+        assert!(config.autosave_due(Duration::ZERO, 5));
+
+        // 0 turns a threshold off
+        let edits_only = Config { autosave_edits: 1, ..Config::default() };
+        assert!(!edits_only.autosave_due(Duration::from_secs(u32::MAX.into()), 0));
+        assert!(edits_only.autosave_due(Duration::ZERO, 1));
+        assert!(!Config::default().autosave_due(Duration::from_secs(u32::MAX.into()), usize::MAX));
+    }
+
+    fn company(name: &str, tags: &str) -> Customer {
+        let mut customer = Customer::new();
+        customer.set_company_name(name.to_string());
+        customer.set_tags(tags.to_string());
+        customer
+    }
+
+    fn listed(scroll_buffer: &mut ScrollBuffer, filter: &str) -> Vec<String> {
+        scroll_buffer.apply_filter(filter.to_string());
+        scroll_buffer.get_filtered_customers().iter().map(|c| c.name.clone()).collect()
+    }
+
+    #[test]
+    fn tags_filter_alone_or_with_text() {
+        let mut scroll_buffer = ScrollBuffer::with_size(ColorScheme::new(), 80, 20);
+        scroll_buffer.set_customers(vec![
+            company("Acme", "#vip, supplier"),
+            company("Globex", "VIP"),
+            company("Initech", ""),
+        ]);
+
+        assert_eq!(listed(&mut scroll_buffer, "#VIP"), ["Acme", "Globex"]);
+        assert_eq!(listed(&mut scroll_buffer, "#vip #supplier"), ["Acme"]);
+        assert_eq!(listed(&mut scroll_buffer, "glob #vip"), ["Globex"]);
+        assert!(listed(&mut scroll_buffer, "#vip initech").is_empty());
+        assert!(listed(&mut scroll_buffer, "#nope").is_empty());
+        assert_eq!(listed(&mut scroll_buffer, "").len(), 3);
+    }
+
+    fn rows(scroll_buffer: &ScrollBuffer) -> Vec<Uuid> {
+        scroll_buffer.filtered.iter().map(|i| scroll_buffer.buffer[*i].id).collect()
+    }
+
+    #[test]
+    fn favourites_are_listed_first() {
+        let mut scroll_buffer = ScrollBuffer::with_size(ColorScheme::new(), 80, 20);
+        let acme = company("Acme", "");
+        let mut ann = company("Acme", "");
+        ann.set_contact_name("Ann Lee".to_string());
+        ann.company_id = Some(acme.id);
+        ann.pinned = true;
+        let mut bob = ann.clone();
+        bob.id = Uuid::new_v4();
+        bob.set_contact_name("Bob Hill".to_string());
+        bob.pinned = false;
+        let mut globex = company("Globex", "");
+        globex.pinned = true;
+        let initech = company("Initech", "");
+        scroll_buffer.set_customers(vec![acme.clone(), ann.clone(), bob.clone(), globex.clone(), initech.clone()]);
+        scroll_buffer.expanded.insert(acme.id);
+
+        // A pinned person stands on their own, not under the company
+        scroll_buffer.apply_filter(String::new());
+        assert_eq!(rows(&scroll_buffer), [ann.id, globex.id, acme.id, bob.id, initech.id]);
+
+        scroll_buffer.apply_filter("lee".to_string());
+        assert_eq!(rows(&scroll_buffer), [ann.id]);
+
+        scroll_buffer.favourites_only = true;
+        scroll_buffer.apply_filter(String::new());
+        assert_eq!(rows(&scroll_buffer), [ann.id, globex.id]);
+    }
+}
@@ -0,0 +1,276 @@
+use crate::contacts_file::CURRENT_VERSION;
+use crate::customer::Customer;
+use crate::storage::Storage;
+use rusqlite::{params, Connection, OptionalExtension};
+use chrono::Utc;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+/// Stores each customer as a row, every change is written as it happens.
+///
+/// The record itself is kept as JSON in `data` so new `Customer` fields need
+/// no schema change, `name` and `search` exist for ordering and `query`.
+pub struct SqliteStorage {
+    connection: Connection,
+}
+
+fn to_io(e: rusqlite::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+fn upsert(connection: &Connection, customer: &Customer) -> io::Result<()> {
+    connection.execute(
+        "INSERT INTO customers (id, name, search, updated_at, data) VALUES (?1, ?2, ?3, ?4, ?5)
+         ON CONFLICT (id) DO UPDATE SET
+             name = excluded.name,
+             search = excluded.search,
+             updated_at = excluded.updated_at,
+             data = excluded.data",
+        params![
+            customer.id.to_string(),
+            customer.name,
+            customer.search_text(),
+            customer.updated_at.to_rfc3339(),
+            serde_json::to_string(customer)?
+        ]
+    ).map_err(to_io)?;
+
+    Ok(())
+}
+
+fn read_data(row: &rusqlite::Row) -> rusqlite::Result<String> {
+    row.get(0)
+}
+
+impl SqliteStorage {
+    pub fn open(file_path: PathBuf) -> io::Result<SqliteStorage> {
+        if let Some(parent) = file_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let connection = Connection::open(&file_path).map_err(to_io)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS meta (
+                 key TEXT PRIMARY KEY,
+                 value TEXT NOT NULL
+             );
+             CREATE TABLE IF NOT EXISTS customers (
+                 id TEXT PRIMARY KEY,
+                 name TEXT NOT NULL,
+                 search TEXT NOT NULL,
+                 updated_at TEXT NOT NULL,
+                 data TEXT NOT NULL
+             );
+             CREATE INDEX IF NOT EXISTS customers_name ON customers (name);"
+        ).map_err(to_io)?;
+
+        let version: Option<String> = connection
+            .query_row("SELECT value FROM meta WHERE key = 'version'", [], |row| row.get(0))
+            .optional()
+            .map_err(to_io)?;
+        match version.and_then(|v| v.parse::<u32>().ok()) {
+            Some(version) if version > CURRENT_VERSION => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                    "{} uses format v{} but this version of Rusty CRM only supports up to v{}, please upgrade",
+                    file_path.display(), version, CURRENT_VERSION)));
+            },
+            Some(version) if version == CURRENT_VERSION => {},
+            Some(version) => {
+                // Rows hold customer JSON which reads any older version as
+                // is, only the data needs migrating
+                let file_name = file_path.file_name().and_then(|n| n.to_str()).unwrap_or("contacts.db");
+                let backup_path = file_path.with_file_name(format!("{}.v{}-{}.bak", file_name, version, Utc::now().format("%Y%m%d%H%M%S")));
+                log::info!("Migrating {} from v{} to v{}, keeping a copy at {}",
+                           file_path.display(), version, CURRENT_VERSION, backup_path.display());
+                fs::copy(&file_path, &backup_path)?;
+                let mut storage = SqliteStorage { connection };
+                if version < 3 {
+                    storage.link_companies()?;
+                }
+                storage.connection.execute("UPDATE meta SET value = ?1 WHERE key = 'version'",
+                                           params![CURRENT_VERSION.to_string()]).map_err(to_io)?;
+                return Ok(storage);
+            },
+            None => {
+                connection.execute("INSERT INTO meta (key, value) VALUES ('version', ?1)",
+                                   params![CURRENT_VERSION.to_string()]).map_err(to_io)?;
+            }
+        }
+
+        Ok(SqliteStorage { connection })
+    }
+
+    /// Splits flat company and contact rows into companies with their people.
+    fn link_companies(&mut self) -> io::Result<()> {
+        let mut customers = self.load()?;
+        let changed = Customer::link_companies(&mut customers);
+        let transaction = self.connection.transaction().map_err(to_io)?;
+        for customer in customers.iter().filter(|c| changed.contains(&c.id)) {
+            upsert(&transaction, customer)?;
+        }
+        transaction.commit().map_err(to_io)
+    }
+
+    fn read_rows(&self, sql: &str, query: Option<&str>) -> io::Result<Vec<Customer>> {
+        let mut statement = self.connection.prepare(sql).map_err(to_io)?;
+        let rows = match query {
+            Some(query) => statement.query_map(params![query], read_data),
+            None => statement.query_map([], read_data),
+        }.map_err(to_io)?;
+
+        let mut customers = Vec::new();
+        for data in rows {
+            let customer: Customer = serde_json::from_str(&data.map_err(to_io)?)?;
+            customers.push(customer);
+        }
+        Customer::sort(&mut customers);
+
+        Ok(customers)
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn load(&mut self) -> io::Result<Vec<Customer>> {
+        self.read_rows("SELECT data FROM customers", None)
+    }
+
+    fn insert(&mut self, customer: &Customer) -> io::Result<()> {
+        self.connection.execute(
+            "INSERT INTO customers (id, name, search, updated_at, data) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                customer.id.to_string(),
+                customer.name,
+                customer.search_text(),
+                customer.updated_at.to_rfc3339(),
+                serde_json::to_string(customer)?
+            ]
+        ).map_err(to_io)?;
+
+        Ok(())
+    }
+
+    fn update(&mut self, customer: &Customer) -> io::Result<()> {
+        upsert(&self.connection, customer)
+    }
+
+    fn delete(&mut self, id: Uuid) -> io::Result<()> {
+        self.connection.execute("DELETE FROM customers WHERE id = ?1", params![id.to_string()]).map_err(to_io)?;
+
+        Ok(())
+    }
+
+    fn query(&mut self, query: &str) -> io::Result<Vec<Customer>> {
+        // `search` is already lowercase, escape LIKE wildcards in the query
+        let pattern = query.to_lowercase().replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+        let mut customers = self.read_rows("SELECT data FROM customers WHERE search LIKE '%' || ?1 || '%' ESCAPE '\\'", Some(&pattern))?;
+        customers.retain(|c| c.deleted_at.is_none());
+
+        Ok(customers)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Every change is committed as it is made
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::customer::{Note, NoteKind, PhoneLabel, PhoneNumber};
+    use crate::json_storage::JsonStorage;
+    use crate::storage;
+    use crate::test_dir::TestDir;
+
+    fn person(company: &str, contact: &str) -> Customer {
+        let mut customer = Customer::new();
+        customer.set_company_name(company.to_string());
+        customer.set_contact_name(contact.to_string());
+        customer
+    }
+
+    fn by_id(mut customers: Vec<Customer>) -> Vec<Customer> {
+        customers.sort_by_key(|c| c.id);
+        customers
+    }
+
+    #[test]
+    fn json_to_sqlite_and_back_keeps_every_record() {
+        let dir = TestDir::new("sqlite_convert");
+        let mut company = Customer::new();
+        company.set_company_name("Acme".to_string());
+        company.phones = vec![PhoneNumber::new(PhoneLabel::Office, "07 3333 4444".to_string())];
+        company.ensure_primary();
+        company.notes.push(Note::new(NoteKind::Call, "50% off, isn't_it?".to_string()));
+        company.tags.insert("vip".to_string());
+        let mut ann = person("Acme", "Ann Lee");
+        ann.company_id = Some(company.id);
+        let mut trashed = person("Globex", "Bob Hill");
+        trashed.deleted_at = Some(Utc::now());
+        let customers = vec![company, ann, trashed];
+
+        let mut json = JsonStorage::new(dir.join("contacts.json"), 0);
+        for customer in customers.iter() {
+            json.insert(customer).unwrap();
+        }
+        json.flush().unwrap();
+
+        let mut sqlite = SqliteStorage::open(dir.join("contacts.db")).unwrap();
+        assert_eq!(storage::convert(&mut json, &mut sqlite).unwrap(), 3);
+        assert_eq!(by_id(sqlite.load().unwrap()), by_id(customers.clone()));
+        // The LIKE wildcards in a query are taken literally
+        assert_eq!(sqlite.query("50%").unwrap().len(), 1);
+        assert!(sqlite.query("5_").unwrap().is_empty());
+        assert!(sqlite.query("0%f").unwrap().is_empty());
+        assert!(sqlite.query("bob").unwrap().is_empty());
+
+        let mut back = JsonStorage::new(dir.join("back.json"), 0);
+        assert_eq!(storage::convert(&mut sqlite, &mut back).unwrap(), 3);
+        assert_eq!(by_id(back.load().unwrap()), by_id(customers));
+
+        // Nothing is converted into a store that already has customers
+        let mut json = JsonStorage::new(dir.join("contacts.json"), 0);
+        assert_eq!(storage::convert(&mut json, &mut sqlite).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn old_database_is_migrated() {
+        let dir = TestDir::new("sqlite_migrate");
+        let path = dir.join("contacts.db");
+        let (ann, bob) = (person("Acme", "Ann Lee"), person("acme ", "Bob Hill"));
+        {
+            // A v2 database held flat contact rows, no company records
+            let mut storage = SqliteStorage::open(path.clone()).unwrap();
+            storage.insert(&ann).unwrap();
+            storage.insert(&bob).unwrap();
+            storage.connection.execute("UPDATE meta SET value = '2' WHERE key = 'version'", []).unwrap();
+        }
+
+        let mut storage = SqliteStorage::open(path.clone()).unwrap();
+        let version: String = storage.connection
+            .query_row("SELECT value FROM meta WHERE key = 'version'", [], |row| row.get(0)).unwrap();
+        assert_eq!(version, CURRENT_VERSION.to_string());
+
+        let customers = storage.load().unwrap();
+        assert_eq!(customers.len(), 3);
+        let company = customers.iter().find(|c| c.is_company()).unwrap();
+        assert_eq!(company.name, "Acme");
+        for id in [ann.id, bob.id] {
+            let person = customers.iter().find(|c| c.id == id).unwrap();
+            assert_eq!(person.company_id, Some(company.id));
+            assert_eq!(person.name, "Acme");
+        }
+
+        let backups: Vec<String> = fs::read_dir(dir.path()).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.starts_with("contacts.db.v2-") && name.ends_with(".bak"))
+            .collect();
+        assert_eq!(backups.len(), 1);
+
+        // A database from a newer version is refused
+        storage.connection.execute("UPDATE meta SET value = '99' WHERE key = 'version'", []).unwrap();
+        drop(storage);
+        assert_eq!(SqliteStorage::open(path).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -5,12 +5,18 @@ use std::io::{self, stdout, Write};
 use crossterm::QueueableCommand;
 
 use crate::colors::ColorScheme;
+use chrono::{DateTime, Local};
 
 pub struct StatusLine {
     message: String,
     row: usize,
     cols: usize,
     results: usize,
+    tag_counts: Vec<(String, usize)>,
+    overdue: usize,
+    favourites_only: bool,
+    modified: bool,
+    autosaved_at: Option<DateTime<Local>>,
     color_scheme: ColorScheme
 }
 
@@ -24,12 +30,33 @@ impl StatusLine {
             cols,
             row,
             results: 0,
+            tag_counts: Vec::new(),
+            overdue: 0,
+            favourites_only: false,
+            modified: false,
+            autosaved_at: None,
             color_scheme
         })
     }
     pub fn draw(&self) -> io::Result<()> {
-        let results_string = format!("Results: {}", self.results);
-        let results_offset = results_string.len();
+        let mut results_string = String::new();
+        if self.overdue > 0 {
+            results_string.push_str(&format!("Overdue: {}  ", self.overdue));
+        }
+        if self.modified {
+            results_string.push_str("[Modified]  ");
+        }
+        if let Some(autosaved_at) = self.autosaved_at {
+            results_string.push_str(&format!("Autosaved {}  ", autosaved_at.format("%H:%M:%S")));
+        }
+        if self.favourites_only {
+            results_string.push_str("Favourites only  ");
+        }
+        for (tag, count) in self.tag_counts.iter() {
+            results_string.push_str(&format!("#{}: {}  ", tag, count));
+        }
+        results_string.push_str(&format!("Results: {}", self.results));
+        let results_offset = results_string.chars().count().min(self.cols);
         stdout().queue(SetColors(Colors::new(self.color_scheme.grey, self.color_scheme.black)))?;
         stdout().queue(SavePosition)?;
         stdout().queue(MoveTo(0, self.row as u16))?;
@@ -50,6 +77,45 @@ impl StatusLine {
         Ok(())
     }
 
+    pub fn set_tag_counts(&mut self, tag_counts: Vec<(String, usize)>) -> io::Result<()> {
+        self.tag_counts = tag_counts;
+        self.draw()?;
+
+        Ok(())
+    }
+
+    pub fn set_overdue_count(&mut self, overdue: usize) -> io::Result<()> {
+        if self.overdue != overdue {
+            self.overdue = overdue;
+            self.draw()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn set_favourites_only(&mut self, favourites_only: bool) -> io::Result<()> {
+        self.favourites_only = favourites_only;
+        self.draw()?;
+
+        Ok(())
+    }
+
+    pub fn set_modified(&mut self, modified: bool) -> io::Result<()> {
+        if self.modified != modified {
+            self.modified = modified;
+            self.draw()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn set_autosaved_at(&mut self, autosaved_at: DateTime<Local>) -> io::Result<()> {
+        self.autosaved_at = Some(autosaved_at);
+        self.draw()?;
+
+        Ok(())
+    }
+
     pub fn set_message(&mut self, message: String) -> io::Result<()> {
         self.message = message;
         self.draw()?;
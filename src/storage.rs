@@ -0,0 +1,124 @@
+use crate::customer::Customer;
+use crate::json_storage::JsonStorage;
+use crate::merge::MergeResult;
+use crate::sqlite_storage::SqliteStorage;
+use directories::ProjectDirs;
+use serde::{Serialize, Deserialize};
+use std::io;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Where the customers are persisted.
+pub trait Storage {
+    /// Reads every stored customer.
+    fn load(&mut self) -> io::Result<Vec<Customer>>;
+
+    fn insert(&mut self, customer: &Customer) -> io::Result<()>;
+
+    fn update(&mut self, customer: &Customer) -> io::Result<()>;
+
+    fn delete(&mut self, id: Uuid) -> io::Result<()>;
+
+    /// The customers matching a free text `query`, see `Customer::matches`.
+    fn query(&mut self, query: &str) -> io::Result<Vec<Customer>>;
+
+    /// Persists any changes that are not written through yet.
+    fn flush(&mut self) -> io::Result<()>;
+
+    /// Whether there are changes `flush` has not written yet.
+    fn has_unsaved_changes(&self) -> bool {
+        false
+    }
+
+    /// Whether someone else changed the store since we loaded or saved it.
+    fn changed_externally(&mut self) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    /// Three way merges the external changes into ours, the merged
+    /// customers become the in-memory state but are not flushed.
+    fn merge_external(&mut self) -> io::Result<MergeResult> {
+        Ok(MergeResult { customers: self.load()?, conflicts: Vec::new() })
+    }
+
+    /// Whether the store is encrypted and needs `unlock` before `load`.
+    fn is_encrypted(&self) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    /// Derives the key for an encrypted store from its passphrase, a wrong
+    /// passphrase is reported by `load`.
+    fn unlock(&mut self, _passphrase: &str) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "this storage backend does not support encryption"))
+    }
+
+    /// Takes an advisory lock for as long as the store is open. Returns
+    /// false when another instance already holds it.
+    fn lock(&mut self) -> io::Result<bool> {
+        Ok(true)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    #[default]
+    Json,
+    Sqlite,
+}
+
+impl StorageKind {
+    /// Guesses the backend from a file extension.
+    pub fn from_path(path: &Path) -> Option<StorageKind> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("db") | Some("sqlite") | Some("sqlite3") => Some(StorageKind::Sqlite),
+            Some("json") => Some(StorageKind::Json),
+            _ => None,
+        }
+    }
+
+    /// The backend to use: the command line flag wins, then the extension of
+    /// the file name given, then the config.
+    pub fn resolve(flag: Option<StorageKind>, file_path: Option<&Path>, config: StorageKind) -> StorageKind {
+        flag.or_else(|| file_path.and_then(StorageKind::from_path)).unwrap_or(config)
+    }
+
+    pub fn default_path(&self) -> PathBuf {
+        let proj_dirs = ProjectDirs::from("au", "popplestones", "RustyCrm").expect("Failed to get project directory");
+        match self {
+            StorageKind::Json => proj_dirs.config_dir().join("contacts.json"),
+            StorageKind::Sqlite => proj_dirs.config_dir().join("contacts.db"),
+        }
+    }
+
+    pub fn open(&self, path: PathBuf, backups: usize) -> io::Result<Box<dyn Storage>> {
+        log::info!("Opening {:?} storage at {}", self, path.display());
+        match self {
+            StorageKind::Json => Ok(Box::new(JsonStorage::new(path, backups))),
+            StorageKind::Sqlite => Ok(Box::new(SqliteStorage::open(path)?)),
+        }
+    }
+}
+
+/// Copies every customer from one store into another, the destination
+/// must be empty.
+pub fn convert(source: &mut dyn Storage, destination: &mut dyn Storage) -> io::Result<usize> {
+    let customers = source.load()?;
+
+    let existing = match destination.load() {
+        Ok(existing) => existing.len(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e),
+    };
+    if existing > 0 {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                  format!("destination already contains {} customers", existing)));
+    }
+
+    for customer in customers.iter() {
+        destination.insert(customer)?;
+    }
+    destination.flush()?;
+
+    Ok(customers.len())
+}
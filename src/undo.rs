@@ -0,0 +1,148 @@
+use crate::customer::Customer;
+use uuid::Uuid;
+
+/// A single mutation of the customer list, with enough state to revert it.
+#[derive(Debug, Clone)]
+pub enum Change {
+    Added(Customer),
+    Updated { before: Box<Customer>, after: Box<Customer> },
+    Deleted(Customer),
+}
+
+impl Change {
+    pub fn customer_id(&self) -> Uuid {
+        match self {
+            Change::Added(customer) | Change::Deleted(customer) => customer.id,
+            Change::Updated { after, .. } => after.id,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let (action, customer) = match self {
+            Change::Added(customer) => ("add", customer),
+            Change::Updated { after, .. } => ("edit", &**after),
+            Change::Deleted(customer) => ("delete", customer),
+        };
+        format!("{} of {}", action, if customer.name.is_empty() { "(none)" } else { &customer.name })
+    }
+}
+
+/// Undo and redo stacks, kept for the whole session.
+pub struct UndoHistory {
+    undo: Vec<Change>,
+    redo: Vec<Change>,
+}
+
+impl UndoHistory {
+    pub fn new() -> UndoHistory {
+        UndoHistory {
+            undo: Vec::new(),
+            redo: Vec::new(),
+        }
+    }
+
+    /// Records a new change, anything that was undone can no longer be redone.
+    pub fn record(&mut self, change: Change) {
+        self.undo.push(change);
+        self.redo.clear();
+    }
+
+    /// The change to revert, it moves onto the redo stack.
+    pub fn undo(&mut self) -> Option<Change> {
+        let change = self.undo.pop()?;
+        self.redo.push(change.clone());
+        Some(change)
+    }
+
+    /// The change to apply again, it moves back onto the undo stack.
+    pub fn redo(&mut self) -> Option<Change> {
+        let change = self.redo.pop()?;
+        self.undo.push(change.clone());
+        Some(change)
+    }
+
+    /// Drops every change touching the customer, used once it is purged so
+    /// undo can not bring it back.
+    pub fn forget(&mut self, id: Uuid) {
+        self.undo.retain(|c| c.customer_id() != id);
+        self.redo.retain(|c| c.customer_id() != id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn customer(name: &str) -> Customer {
+        let mut customer = Customer::new();
+        customer.set_company_name(name.to_string());
+        customer
+    }
+
+    fn updated(before: &Customer, name: &str) -> Change {
+        let mut after = before.clone();
+        after.set_company_name(name.to_string());
+        Change::Updated { before: Box::new(before.clone()), after: Box::new(after) }
+    }
+
+    #[test]
+    fn changes_undo_and_redo_in_order() {
+        let mut history = UndoHistory::new();
+        let acme = customer("Acme");
+        let globex = customer("Globex");
+        history.record(Change::Added(acme.clone()));
+        history.record(updated(&acme, "Acme Pty"));
+        history.record(Change::Deleted(globex.clone()));
+
+        // Undone newest first
+        assert_eq!(history.undo().unwrap().describe(), "delete of Globex");
+        match history.undo() {
+            Some(Change::Updated { before, after }) => {
+                assert_eq!(before.name, "Acme");
+                assert_eq!(after.name, "Acme Pty");
+            },
+            other => panic!("expected the edit, got {:?}", other),
+        }
+        assert_eq!(history.undo().unwrap().describe(), "add of Acme");
+        assert!(history.undo().is_none());
+
+        // Redone oldest first
+        assert_eq!(history.redo().unwrap().describe(), "add of Acme");
+        assert_eq!(history.redo().unwrap().describe(), "edit of Acme Pty");
+        assert_eq!(history.redo().unwrap().customer_id(), globex.id);
+        assert!(history.redo().is_none());
+        assert_eq!(history.undo().unwrap().describe(), "delete of Globex");
+    }
+
+    #[test]
+    fn new_change_clears_redo() {
+        let mut history = UndoHistory::new();
+        let acme = customer("Acme");
+        history.record(Change::Added(acme.clone()));
+        history.record(updated(&acme, "Acme Pty"));
+        history.undo();
+
+        history.record(updated(&acme, "Acme Ltd"));
+        assert!(history.redo().is_none());
+        assert_eq!(history.undo().unwrap().describe(), "edit of Acme Ltd");
+        assert_eq!(history.undo().unwrap().describe(), "add of Acme");
+    }
+
+    #[test]
+    fn forget_drops_a_purged_customer() {
+        let mut history = UndoHistory::new();
+        let acme = customer("Acme");
+        let globex = customer("Globex");
+        history.record(Change::Added(acme.clone()));
+        history.record(Change::Added(globex.clone()));
+        history.record(updated(&acme, "Acme Pty"));
+        history.record(Change::Deleted(acme.clone()));
+        // Leaves the delete to redo
+        history.undo();
+
+        history.forget(acme.id);
+        assert!(history.redo().is_none());
+        assert_eq!(history.undo().unwrap().customer_id(), globex.id);
+        assert!(history.undo().is_none());
+    }
+}
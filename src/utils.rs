@@ -1,7 +1,9 @@
 use crossterm::terminal::{enable_raw_mode, disable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
 use crossterm::cursor::SetCursorStyle;
 use crossterm::ExecutableCommand;
+use directories::BaseDirs;
 use std::io::stdout;
+use std::path::PathBuf;
 
 pub struct RawMode;
 
@@ -21,3 +23,14 @@ impl Drop for RawMode {
         let _ = disable_raw_mode();
     }
 }
+
+/// A path typed by the user, a leading `~` is their home directory.
+pub fn expand_path(path: &str) -> PathBuf {
+    let home = BaseDirs::new().map(|dirs| dirs.home_dir().to_path_buf());
+    match (path.strip_prefix('~'), home) {
+        (Some(rest), Some(home)) if rest.is_empty() || rest.starts_with('/') => {
+            home.join(rest.trim_start_matches('/'))
+        },
+        _ => PathBuf::from(path),
+    }
+}
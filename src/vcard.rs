@@ -0,0 +1,432 @@
+use crate::customer::{Customer, PhoneLabel, PhoneNumber};
+use std::collections::HashSet;
+use std::fmt::{self, Display, Formatter};
+
+/// One `NAME;PARAM=value:value` line of a vCard.
+#[derive(Debug)]
+struct Property {
+    name: String,
+    params: Vec<(String, String)>,
+    value: String,
+}
+
+impl Property {
+    /// Parses an unfolded line, the group prefix (`item1.TEL`) is dropped.
+    fn parse(line: &str) -> Option<Property> {
+        let (head, value) = split_unquoted(line, ':')?;
+        let mut parts = head.split(';');
+        let name = parts.next()?.trim();
+        let name = name.rsplit('.').next().unwrap_or(name).to_uppercase();
+        let params = parts
+            .map(|param| match param.split_once('=') {
+                Some((key, value)) => (key.trim().to_uppercase(), value.trim().trim_matches('"').to_string()),
+                // vCard 2.1 allows bare types, e.g. `TEL;CELL:`
+                None => ("TYPE".to_string(), param.trim().to_string()),
+            })
+            .collect();
+
+        Some(Property { name, params, value: value.to_string() })
+    }
+
+    /// Every TYPE given, whether as one list or repeated.
+    fn types(&self) -> Vec<&str> {
+        self.params.iter()
+            .filter(|(key, _)| key == "TYPE")
+            .flat_map(|(_, value)| value.split(','))
+            .map(|t| t.trim())
+            .filter(|t| !t.is_empty())
+            .collect()
+    }
+
+    fn has_type(&self, name: &str) -> bool {
+        self.types().iter().any(|t| t.eq_ignore_ascii_case(name))
+    }
+
+    fn is_preferred(&self) -> bool {
+        self.has_type("pref") || self.params.iter().any(|(key, _)| key == "PREF")
+    }
+
+    fn text(&self) -> String {
+        unescape(&self.value)
+    }
+
+    /// The `;` separated components of a structured value like ORG or N.
+    fn components(&self) -> Vec<String> {
+        split_escaped(&self.value).iter().map(|c| unescape(c)).collect()
+    }
+}
+
+/// What an import did with each entry it read.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ImportSummary {
+    pub added: usize,
+    /// Entries without a name, phone number or email
+    pub skipped: usize,
+    /// Entries matching a customer we already have or an earlier entry
+    pub duplicates: usize,
+}
+
+impl Display for ImportSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} added, {} skipped, {} duplicates", self.added, self.skipped, self.duplicates)
+    }
+}
+
+/// Reads every card in a vCard 2.1, 3.0 or 4.0 file and returns the new
+/// customers, leaving out those already in `existing`.
+pub fn import(text: &str, existing: &[Customer]) -> (Vec<Customer>, ImportSummary) {
+    keep_new(cards(text).iter().map(|card| to_customer(card)), existing)
+}
+
+/// Sorts the customers read by an import into new ones, duplicates and
+/// entries without anything to keep, which are `None`.
+pub fn keep_new(found: impl IntoIterator<Item = Option<Customer>>, existing: &[Customer]) -> (Vec<Customer>, ImportSummary) {
+    let mut summary = ImportSummary::default();
+    let mut seen: HashSet<String> = existing.iter().flat_map(duplicate_keys).collect();
+    let mut customers = Vec::new();
+
+    for customer in found {
+        let customer = match customer {
+            Some(customer) => customer,
+            None => {
+                summary.skipped += 1;
+                continue;
+            }
+        };
+        let keys = duplicate_keys(&customer);
+        if keys.iter().any(|key| seen.contains(key)) {
+            summary.duplicates += 1;
+            continue;
+        }
+        seen.extend(keys);
+        customers.push(customer);
+        summary.added += 1;
+    }
+
+    (customers, summary)
+}
+
+/// Two customers are the same when they have the same company and contact
+/// name, or share a phone number or email.
+fn duplicate_keys(customer: &Customer) -> Vec<String> {
+    let mut keys = Vec::new();
+    let name = customer.name.trim().to_lowercase();
+    let contact = customer.contact_name.as_deref().unwrap_or("").trim().to_lowercase();
+    if !name.is_empty() || !contact.is_empty() {
+        keys.push(format!("name:{}\n{}", name, contact));
+    }
+    keys.extend(customer.phones.iter()
+        .map(|p| p.number.chars().filter(|c| c.is_ascii_digit()).collect::<String>())
+        .filter(|digits| !digits.is_empty())
+        .map(|digits| format!("tel:{}", digits)));
+    keys.extend(customer.emails.iter()
+        .map(|e| e.trim().to_lowercase())
+        .filter(|e| !e.is_empty())
+        .map(|e| format!("email:{}", e)));
+    keys
+}
+
+/// Writes the customers as vCard 3.0, which phones and mail clients read
+/// most widely.
+pub fn export<'a>(customers: impl IntoIterator<Item = &'a Customer>) -> String {
+    let mut text = String::new();
+    for customer in customers {
+        let mut lines = vec!["BEGIN:VCARD".to_string(), "VERSION:3.0".to_string()];
+        lines.push(format!("UID:urn:uuid:{}", customer.id));
+
+        let contact = customer.contact_name.as_deref().filter(|n| !n.trim().is_empty());
+        let full_name = contact.unwrap_or(&customer.name);
+        lines.push(format!("FN:{}", escape(full_name)));
+        // N is family;given;additional;prefix;suffix
+        let (given, family) = match contact {
+            Some(contact) => contact.trim().rsplit_once(' ').unwrap_or(("", contact.trim())),
+            None => ("", customer.name.as_str()),
+        };
+        lines.push(format!("N:{};{};;;", escape(family), escape(given)));
+        if !customer.name.is_empty() {
+            lines.push(format!("ORG:{}", escape(&customer.name)));
+        }
+
+        for phone in customer.phones.iter() {
+            let mut types = match &phone.label {
+                PhoneLabel::Office => "WORK,VOICE".to_string(),
+                PhoneLabel::Mobile => "CELL".to_string(),
+                PhoneLabel::Fax => "WORK,FAX".to_string(),
+                // Quoted so spaces survive, characters that end a parameter are dropped
+                PhoneLabel::Custom(label) => format!("\"{}\"", label.replace(['"', ';', ':', ','], "")),
+            };
+            if phone.primary {
+                types.push_str(",PREF");
+            }
+            lines.push(format!("TEL;TYPE={}:{}", types, escape(&phone.number)));
+        }
+        for email in customer.emails.iter() {
+            lines.push(format!("EMAIL;TYPE=INTERNET:{}", escape(email)));
+        }
+        lines.push("END:VCARD".to_string());
+
+        for line in lines {
+            text.push_str(&fold(&line));
+        }
+    }
+
+    text
+}
+
+/// Breaks a content line into 75 octet pieces, continuation lines start
+/// with a space.
+fn fold(line: &str) -> String {
+    let mut folded = String::new();
+    let mut width = 0;
+    for c in line.chars() {
+        if width + c.len_utf8() > 75 {
+            folded.push_str("\r\n ");
+            width = 1;
+        }
+        folded.push(c);
+        width += c.len_utf8();
+    }
+    folded.push_str("\r\n");
+    folded
+}
+
+fn escape(value: &str) -> String {
+    let mut text = String::new();
+    for c in value.chars() {
+        match c {
+            '\\' => text.push_str("\\\\"),
+            ',' => text.push_str("\\,"),
+            ';' => text.push_str("\\;"),
+            '\n' => text.push_str("\\n"),
+            '\r' => {},
+            c => text.push(c),
+        }
+    }
+    text
+}
+
+/// Splits the file into cards of unfolded properties.
+fn cards(text: &str) -> Vec<Vec<Property>> {
+    let mut cards = Vec::new();
+    let mut current: Option<Vec<Property>> = None;
+
+    for line in unfold(text) {
+        let property = match Property::parse(&line) {
+            Some(property) => property,
+            None => continue,
+        };
+        match (property.name.as_str(), property.value.trim().to_uppercase().as_str()) {
+            ("BEGIN", "VCARD") => current = Some(Vec::new()),
+            ("END", "VCARD") => cards.extend(current.take()),
+            _ => {
+                if let Some(card) = current.as_mut() {
+                    card.push(property);
+                }
+            }
+        }
+    }
+
+    cards
+}
+
+/// Joins folded lines, a line starting with a space or tab continues the
+/// previous one.
+fn unfold(text: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for line in text.lines() {
+        match (line.strip_prefix(' ').or_else(|| line.strip_prefix('\t')), lines.last_mut()) {
+            (Some(rest), Some(last)) => last.push_str(rest),
+            _ => lines.push(line.to_string()),
+        }
+    }
+    lines
+}
+
+fn to_customer(card: &[Property]) -> Option<Customer> {
+    let mut customer = Customer::new();
+    let find = |name: &str| card.iter().find(|p| p.name == name);
+
+    let org = find("ORG").and_then(|p| p.components().into_iter().find(|c| !c.trim().is_empty()));
+    let full_name = find("FN").map(|p| p.text())
+        .filter(|n| !n.trim().is_empty())
+        .or_else(|| find("N").map(|p| {
+            // N is family;given;additional;prefix;suffix
+            let parts = p.components();
+            let given = parts.get(1).map(|s| s.trim()).unwrap_or("");
+            let family = parts.first().map(|s| s.trim()).unwrap_or("");
+            format!("{} {}", given, family).trim().to_string()
+        }))
+        .filter(|n| !n.is_empty());
+
+    match (org, full_name) {
+        (Some(org), full_name) => {
+            customer.set_company_name(org.trim().to_string());
+            // FN repeats ORG on a card for the company itself
+            customer.contact_name = full_name.map(|n| n.trim().to_string()).filter(|n| *n != customer.name);
+        },
+        // A person without a company is listed under their own name
+        (None, Some(full_name)) => customer.set_company_name(full_name.trim().to_string()),
+        (None, None) => {},
+    }
+
+    for property in card.iter().filter(|p| p.name == "TEL") {
+        let number = property.text();
+        // vCard 4.0 may give the number as a `tel:` URI
+        let number = number.trim().trim_start_matches("tel:").trim();
+        if number.is_empty() {
+            continue;
+        }
+        let mut phone = PhoneNumber::new(phone_label(property), number.to_string());
+        phone.primary = property.is_preferred() && !customer.phones.iter().any(|p| p.primary);
+        customer.phones.push(phone);
+    }
+    customer.ensure_primary();
+
+    customer.emails = card.iter()
+        .filter(|p| p.name == "EMAIL")
+        .map(|p| p.text().trim().to_string())
+        .filter(|e| !e.is_empty())
+        .collect();
+
+    if customer.name.is_empty() && customer.phones.is_empty() && customer.emails.is_empty() {
+        return None;
+    }
+
+    Some(customer)
+}
+
+fn phone_label(property: &Property) -> PhoneLabel {
+    if property.has_type("cell") {
+        PhoneLabel::Mobile
+    } else if property.has_type("fax") {
+        PhoneLabel::Fax
+    } else if property.has_type("work") {
+        PhoneLabel::Office
+    } else {
+        let other = property.types().into_iter()
+            .find(|t| !["voice", "pref", "text", "internet"].iter().any(|known| t.eq_ignore_ascii_case(known)));
+        match other {
+            Some(other) => PhoneLabel::from(other.to_string()),
+            None => PhoneLabel::Office,
+        }
+    }
+}
+
+/// Splits at the first `separator` outside double quotes, parameters may
+/// quote values containing `:`.
+fn split_unquoted(line: &str, separator: char) -> Option<(&str, &str)> {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => return Some((&line[..i], &line[i + 1..])),
+            _ => {},
+        }
+    }
+    None
+}
+
+/// Splits a structured value at the `;` that are not escaped.
+fn split_escaped(value: &str) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                let last = parts.last_mut().expect("parts is never empty");
+                last.push('\\');
+                last.extend(chars.next());
+            },
+            ';' => parts.push(String::new()),
+            c => parts.last_mut().expect("parts is never empty").push(c),
+        }
+    }
+    parts
+}
+
+fn unescape(value: &str) -> String {
+    let mut text = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            text.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') | Some('N') => text.push('\n'),
+            Some(other) => text.push(other),
+            None => text.push('\\'),
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn customer(name: &str, contact: Option<&str>) -> Customer {
+        let mut customer = Customer::new();
+        customer.set_company_name(name.to_string());
+        customer.contact_name = contact.map(|c| c.to_string());
+        customer
+    }
+
+    fn phone(label: PhoneLabel, number: &str, primary: bool) -> PhoneNumber {
+        PhoneNumber { label, number: number.to_string(), primary }
+    }
+
+    #[test]
+    fn export_then_import_round_trips() {
+        let mut jane = customer("Smith, Jones; Partners", Some("Jane van der Berg"));
+        jane.phones = vec![
+            phone(PhoneLabel::Office, "07 3333 4444", false),
+            phone(PhoneLabel::Mobile, "0412 345 678", true),
+            phone(PhoneLabel::Fax, "07 3333 4445", false),
+            phone(PhoneLabel::Custom("After hours".to_string()), "07 3333 4446", false),
+        ];
+        jane.emails = vec!["jane@example.com".to_string(), "accounts@example.com".to_string()];
+        // Long enough to be folded, with characters wider than one octet
+        let mut cafe = customer(&"Café Ümlaut ".repeat(8), None);
+        cafe.phones = vec![phone(PhoneLabel::Office, "02 5555 1234", true)];
+        let originals = vec![jane, cafe];
+
+        let text = export(&originals);
+        assert!(text.lines().all(|line| line.len() <= 75));
+        let (imported, summary) = import(&text, &[]);
+        assert_eq!(summary, ImportSummary { added: 2, skipped: 0, duplicates: 0 });
+
+        for (original, imported) in originals.iter().zip(imported.iter()) {
+            assert_eq!(imported.name, original.name.trim());
+            assert_eq!(imported.contact_name, original.contact_name);
+            assert_eq!(imported.phones, original.phones);
+            assert_eq!(imported.emails, original.emails);
+        }
+    }
+
+    #[test]
+    fn reimporting_an_export_finds_only_duplicates() {
+        let mut acme = customer("Acme", Some("Bob Jones"));
+        acme.phones = vec![phone(PhoneLabel::Office, "07 3333 5555", true)];
+        let existing = vec![acme, customer("Lonely Co", None)];
+
+        let (imported, summary) = import(&export(&existing), &existing);
+        assert!(imported.is_empty());
+        assert_eq!(summary, ImportSummary { added: 0, skipped: 0, duplicates: 2 });
+    }
+
+    #[test]
+    fn older_versions_import() {
+        let text = "BEGIN:VCARD\nVERSION:2.1\nN:Jones;Bob\nTEL;CELL;PREF:0412 000 111\nEND:VCARD\n\
+                    BEGIN:VCARD\nVERSION:4.0\nFN:Ann Lee\nORG:Lee Pty Ltd;Sales\nTEL;VALUE=uri;TYPE=work:tel:07 1111 2222\n\
+                    EMAIL:ann@example.com\nEND:VCARD\nBEGIN:VCARD\nVERSION:3.0\nNOTE:nothing to keep\nEND:VCARD\n";
+
+        let (imported, summary) = import(text, &[]);
+        assert_eq!(summary, ImportSummary { added: 2, skipped: 1, duplicates: 0 });
+        assert_eq!(imported[0].name, "Bob Jones");
+        assert_eq!(imported[0].phones, vec![phone(PhoneLabel::Mobile, "0412 000 111", true)]);
+        assert_eq!(imported[1].name, "Lee Pty Ltd");
+        assert_eq!(imported[1].contact_name.as_deref(), Some("Ann Lee"));
+        assert_eq!(imported[1].phones, vec![phone(PhoneLabel::Office, "07 1111 2222", true)]);
+    }
+}